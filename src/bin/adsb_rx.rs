@@ -17,7 +17,11 @@ use embassy_futures::select::{select, Either};
 use core::fmt::Write;
 use heapless::String;
 
-use lr2021_apps::board::{BoardNucleoL476Rg, ButtonPressKind, LedMode, Lr2021Stm32};
+use lr2021_apps::{
+    board::{BoardNucleoL476Rg, ButtonPressKind, LedMode, Lr2021Stm32},
+    pkt_utils::take_rx_stats,
+    radio_utils::{set_rx_gain_manual, RX_GAIN_MAX},
+};
 use lr2021::{
     ook::*,
     radio::{RxBoost, RxPath},
@@ -73,7 +77,7 @@ async fn main(spawner: Spawner) {
     lr2021.force_crc_out().await.expect("CrcOut"); // Output CRC even if already checked, mainly for debug
 
     // Setup radio to max gain (saturation unlikely in ADS-B and AGC might induce packet loss)
-    lr2021.set_rx_gain(13).await.expect("SetGain");
+    set_rx_gain_manual(&mut lr2021, RX_GAIN_MAX).await.expect("SetGain");
     lr2021.set_rx_continous().await.expect("SetRX");
 
     // Adjust the detection threshold to avoid false detection due to high noise level
@@ -88,10 +92,10 @@ async fn main(spawner: Spawner) {
                 match press {
                     // Short Press: show stats and clean it
                     ButtonPressKind::Short => {
-                        let stats = lr2021.get_ook_rx_stats().await.expect("RxStats");
-                        lr2021.clear_rx_stats().await.expect("ClearStats");
+                        let stats = take_rx_stats::<OokRxStatsRsp>(&mut lr2021).await.expect("RxStats");
                         info!("RX Stats: nb={}, err={}", stats.pkt_rx(), stats.crc_error());
                     }
+                    ButtonPressKind::LongRepeat => {}
                     // Long press: measure RSSI and adjust detection threshold
                     ButtonPressKind::Long => {
                         auto_thr(&mut lr2021).await;
@@ -4,29 +4,43 @@
 //! # BLE TX/RX Demo application
 //!
 //! Long press on user button switch the board role between TX and RX
-//! Double press change the advertising channel (OOB/37/38/39)
+//! Double press change the advertising channel (OOB/37/38/39), and the PHY (1M/2M/Coded S2/Coded S8) when going back to OOB
 //! Short press while in TX mode, send an packet advertising packet
 //! Short press while in RX mode, switch to scan mode on all recently seens address
+//! In scan mode, a connection request is sent to connectable advertisers and the first connection event is run as central
 
 use defmt::*;
 use {defmt_rtt as _, panic_probe as _};
 
 use embassy_executor::Spawner;
 use embassy_futures::select::{select, Either};
+use embassy_stm32::exti::ExtiInput;
+use embassy_time::{Duration, Instant};
 
 use lr2021_apps::{
-    ble_adv::{parse_and_print_ble_adv, parse_ble_adv_hdr, print_ble_adv, AddrList, BleAdvType},
+    ble_adv::{
+        ble_connection_event, ble_send_connect_ind, ble_send_scan_req, parse_and_print_ble_adv, parse_ble_adv_hdr, print_ble_adv,
+        AddrList, BleAdvType, BleChannel, BleConnection, BleLlData,
+    },
     board::{BoardNucleoL476Rg, BoardRole, ButtonPressKind, LedMode, Lr2021Stm32},
+    pkt_utils::rx_packet_len,
+    radio_utils::{disable_auto_txrx, set_auto_txrx},
 };
 use lr2021::{
     ble::*,
-    radio::{FallbackMode, PacketType, RampTime, RxBoost, RxPath},
-    status::{Intr, IRQ_MASK_RX_DONE, IRQ_MASK_TX_DONE},
+    radio::{AutoTxrxMode, FallbackMode, PacketType, RampTime, RxBoost, RxPath},
+    status::{Intr, IRQ_MASK_RX_DONE, IRQ_MASK_TIMEOUT, IRQ_MASK_TX_DONE},
     system::{ChipMode, DioNum},
 };
 
 const VERBOSE: bool = false;
 
+/// Device address (also used in the beacon)
+const OWN_ADDR: u64 = 0xa463ef8c89e6;
+/// Access address and CRC init used for connections
+const CONN_ACCESS_ADDR: u32 = 0x5065_17a9;
+const CONN_CRC_INIT: u32 = 0x3a_c5f1;
+
 /// Packet sent in TX mode
 const ADV_BEACON : [u8;28] = [
     // Header: 2=ADV_IND, with 26 bytes
@@ -48,21 +62,20 @@ const ADV_BEACON : [u8;28] = [
 pub enum AdvChanRf {Chan37, Chan38, Chan39, ChanOob}
 
 impl AdvChanRf {
-    pub fn freq(&self) -> u32 {
+    /// BLE advertising channel, None for the out-of-band channel
+    pub fn ble_chan(&self) -> Option<BleChannel> {
         match self {
-            AdvChanRf::Chan37 => 2_402_000_000,
-            AdvChanRf::Chan38 => 2_426_000_000,
-            AdvChanRf::Chan39 => 2_480_000_000,
-            AdvChanRf::ChanOob => 2_300_000_000,
+            AdvChanRf::Chan37 => Some(BleChannel::ADV37),
+            AdvChanRf::Chan38 => Some(BleChannel::ADV38),
+            AdvChanRf::Chan39 => Some(BleChannel::ADV39),
+            AdvChanRf::ChanOob => None,
         }
     }
+    pub fn freq(&self) -> u32 {
+        self.ble_chan().map(|c| c.frequency_hz()).unwrap_or(2_300_000_000)
+    }
     pub fn whit_init(&self) -> u8 {
-        match self {
-            AdvChanRf::Chan37 => 0x53,
-            AdvChanRf::Chan38 => 0x33,
-            AdvChanRf::Chan39 => 0x73,
-            AdvChanRf::ChanOob => 0xCD,
-        }
+        self.ble_chan().map(|c| c.whitening_init()).unwrap_or(0xCD)
     }
     pub fn next(&mut self) {
         *self = match self {
@@ -85,6 +98,7 @@ async fn main(spawner: Spawner) {
 
     // Select Out-of-band channel to avoid immediately picking BLE traffic and allow board-to-board communication
     let mut chan = AdvChanRf::ChanOob;
+    let mut phy = BleMode::Le1mb;
 
     // Wait for a button press for actions
     let mut button_press = BoardNucleoL476Rg::get_button_evt();
@@ -113,10 +127,10 @@ async fn main(spawner: Spawner) {
     lr2021.set_rx(0xFFFFFFFF, true).await.expect("SetRX");
 
     // Set DIO7 as IRQ for TX/RX Done
-    lr2021.set_dio_irq(DioNum::Dio7, Intr::new(IRQ_MASK_TX_DONE|IRQ_MASK_RX_DONE)).await.expect("Setting DIO7 as IRQ");
+    lr2021.set_dio_irq(DioNum::Dio7, Intr::new(IRQ_MASK_TX_DONE|IRQ_MASK_RX_DONE|IRQ_MASK_TIMEOUT)).await.expect("Setting DIO7 as IRQ");
 
     // Keep a list of address seen to avoid spamming
-    let mut addr_seen = AddrList::new(0xa463ef8c89e6);
+    let mut addr_seen = AddrList::new(OWN_ADDR);
 
     let mut role = BoardRole::Rx;
 
@@ -134,6 +148,7 @@ async fn main(spawner: Spawner) {
                         info!("[RX] Switching to {} | Stats: RX={}, CRC err={}, Len err={}",
                             role, stat.pkt_rx(), stat.crc_error(), stat.len_error());
                     }
+                    (ButtonPressKind::LongRepeat, _) => {}
                     // Long press: switch role TX/RX
                     (ButtonPressKind::Long, _) => {
                         role.toggle();
@@ -142,6 +157,13 @@ async fn main(spawner: Spawner) {
                     // Double press => change channel
                     (ButtonPressKind::Double, r) => {
                         chan.next();
+                        // Change PHY after going through all channels
+                        if chan == AdvChanRf::ChanOob {
+                            phy = next_phy(phy);
+                            info!("Switching to PHY {}", phy);
+                            lr2021.set_chip_mode(ChipMode::Fs).await.expect("SetFs");
+                            lr2021.set_ble_modulation(phy).await.expect("SetBleModulation");
+                        }
                         switch_channel(&mut lr2021, chan, &addr_seen, r.is_rx()).await;
                     }
                 }
@@ -172,8 +194,11 @@ async fn main(spawner: Spawner) {
                                 lr2021.set_chip_mode(ChipMode::Fs).await.expect("SetFs");
                                 match hdr.get_type() {
                                     BleAdvType::AdvInd |
-                                    BleAdvType::AdvDirectInd => send_req(&mut lr2021, BleAdvType::ConnectInd, addr).await,
-                                    BleAdvType::AdvScanInd   => send_req(&mut lr2021, BleAdvType::ScanReq, addr).await,
+                                    BleAdvType::AdvDirectInd => {
+                                        connect(&mut lr2021, &mut irq, addr).await;
+                                        restore_adv(&mut lr2021, chan).await;
+                                    }
+                                    BleAdvType::AdvScanInd   => send_scan_req(&mut lr2021, addr).await,
                                     _ => {
                                         print_ble_adv(&mut addr_seen, &lr2021.buffer()[..nb_byte], hdr, addr, rssi_dbm);
                                     }
@@ -196,6 +221,16 @@ async fn main(spawner: Spawner) {
     }
 }
 
+/// Cycle through the BLE PHYs: 1Mb/s, 2Mb/s and Coded (S=2 / S=8)
+fn next_phy(phy: BleMode) -> BleMode {
+    match phy {
+        BleMode::Le1mb => BleMode::Le2mb,
+        BleMode::Le2mb => BleMode::LeCodedS2,
+        BleMode::LeCodedS2 => BleMode::LeCodedS8,
+        _ => BleMode::Le1mb,
+    }
+}
+
 async fn switch_channel(lr2021: &mut Lr2021Stm32, chan: AdvChanRf, addr_seen: &AddrList, is_rx: bool) {
     let intr = lr2021.get_and_clear_irq().await.expect("GetIrqs");
     let stat = lr2021.get_ble_rx_stats().await.expect("RX Stats");
@@ -226,20 +261,49 @@ async fn send_beacon(lr2021: &mut Lr2021Stm32) {
     lr2021.set_ble_tx(len as u8).await.expect("SetTx");
 }
 
-async fn send_req(lr2021: &mut Lr2021Stm32, req_type: BleAdvType, addr: u64) {
-    let len = 14;
-    lr2021.buffer_mut()[0] = req_type as u8;
-    lr2021.buffer_mut()[1] = 12;
-    lr2021.buffer_mut()[2..8].copy_from_slice(&[0xa4, 0x63, 0xef, 0x8c, 0x89, 0xe6]);
-    lr2021.buffer_mut()[8 ] = ((addr >> 40) & 0xFF) as u8;
-    lr2021.buffer_mut()[9 ] = ((addr >> 32) & 0xFF) as u8;
-    lr2021.buffer_mut()[10] = ((addr >> 24) & 0xFF) as u8;
-    lr2021.buffer_mut()[11] = ((addr >> 16) & 0xFF) as u8;
-    lr2021.buffer_mut()[12] = ((addr >>  8) & 0xFF) as u8;
-    lr2021.buffer_mut()[13] = ( addr        & 0xFF) as u8;
-    lr2021.wr_tx_fifo(len).await.expect("FIFO write");
-    info!("[TX] Sending Scan request to {:06x}", addr);
-    lr2021.set_ble_tx(len as u8).await.expect("SetTx");
+async fn send_scan_req(lr2021: &mut Lr2021Stm32, addr: u64) {
+    info!("[TX] Sending {} to {:06x}", BleAdvType::ScanReq, addr);
+    ble_send_scan_req(lr2021, addr, OWN_ADDR).await.expect("SendScanReq");
+}
+
+/// Send a connection request and run the first connection event as central:
+/// send an empty data PDU on the first data channel and listen for the peripheral answer
+async fn connect(lr2021: &mut Lr2021Stm32, irq: &mut ExtiInput<'static>, addr: u64) {
+    let ll = BleLlData::new(CONN_ACCESS_ADDR, CONN_CRC_INIT);
+    let mut conn = BleConnection::new(ll);
+    info!("[TX] Sending {} to {:06x}", BleAdvType::ConnectInd, addr);
+    ble_send_connect_ind(lr2021, addr, OWN_ADDR, &ll).await.expect("SendConnectInd");
+    irq.wait_for_high().await;
+    let end_req = Instant::now();
+    lr2021.get_and_clear_irq().await.expect("GetIrqs");
+    // Send the first packet in the middle of the transmit window
+    let (win_start, win_size) = ll.transmit_window();
+    let anchor = end_req + win_start + win_size / 2;
+    // Let the chip switch to RX right after the TX: the peripheral answers T_IFS (150us) after our packet,
+    // which is too short to restart the RX from software. Listen for up to 10ms.
+    set_auto_txrx(lr2021, AutoTxrxMode::Always, Duration::from_millis(10), Duration::from_ticks(0)).await.expect("SetAutoTxRx");
+    let chan = ble_connection_event(lr2021, &mut conn, anchor).await.expect("ConnEvent");
+    irq.wait_for_high().await;
+    lr2021.get_and_clear_irq().await.expect("GetIrqs");
+    // Wait for the answer or the RX timeout
+    irq.wait_for_high().await;
+    let intr = lr2021.get_and_clear_irq().await.expect("GetIrqs");
+    disable_auto_txrx(lr2021).await.expect("DisableAutoTxRx");
+    if intr.rx_done() && !intr.crc_error() {
+        let status = lr2021.get_ble_packet_status().await.expect("PktStatus");
+        let nb_byte = status.pkt_len().min(128) as usize;
+        lr2021.rd_rx_fifo(nb_byte).await.expect("RX FIFO Read");
+        info!("[CONN] Data channel {} | {:02x} | RSSI -{}dBm", chan.index(), lr2021.buffer()[..nb_byte], status.rssi_avg()>>1);
+    } else {
+        info!("[CONN] No answer on data channel {} | {}", chan.index(), intr);
+    }
+}
+
+/// Go back to the advertising channel after a connection attempt
+async fn restore_adv(lr2021: &mut Lr2021Stm32, chan: AdvChanRf) {
+    lr2021.set_chip_mode(ChipMode::Fs).await.expect("SetFs");
+    lr2021.set_rf(chan.freq()).await.expect("SetRF");
+    lr2021.set_ble_params(false, ChannelType::Advertiser, chan.whit_init(), 0x555555, 0x8e89bed6).await.expect("Set params");
 }
 
 async fn switch_mode(lr2021: &mut Lr2021Stm32, chan: AdvChanRf, is_rx: bool) {
@@ -258,10 +322,10 @@ async fn switch_mode(lr2021: &mut Lr2021Stm32, chan: AdvChanRf, is_rx: bool) {
 
 
 async fn read_pkt(lr2021: &mut Lr2021Stm32, intr: Intr) -> Option<BlePacketStatusRsp> {
-    let lvl = lr2021.get_rx_fifo_lvl().await.expect("RxFifoLvl");
+    let pkt_len = rx_packet_len(lr2021).await.expect("RxPktLen");
     let pkt_status = lr2021.get_ble_packet_status().await.expect("PktStatus");
     let nb_byte = pkt_status.pkt_len().min(128) as usize;
-    if lvl == 0 && nb_byte != 0 {
+    if pkt_len.is_none() && nb_byte != 0 {
         warn!("No data in fifo ({}) | {}", nb_byte, intr);
         return None;
     }
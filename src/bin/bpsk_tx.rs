@@ -0,0 +1,90 @@
+#![no_std]
+#![no_main]
+
+//! # BPSK TX demo application
+//!
+//! Send a fixed payload in DBPSK (Sigfox-style uplink) at 868.13MHz
+//! Short press send the payload, double press toggle the bitrate between 100 and 600b/s
+//! The red led flash at the end of each transmission
+
+use defmt::*;
+use {defmt_rtt as _, panic_probe as _};
+
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
+
+use lr2021_apps::{
+    board::{BoardNucleoL476Rg, ButtonPressKind, LedMode, Lr2021Stm32},
+    bpsk_utils::{bpsk_tx, set_bpsk_modulation, BpskModulation, BpskRamp},
+    radio_utils::set_rf_auto_path,
+};
+use lr2021::{
+    radio::{PaLfMode, PacketType, RampTime, RxBoost},
+    status::{Intr, IRQ_MASK_TX_DONE},
+    system::{ChipMode, DioNum},
+};
+
+const PAYLOAD: [u8; 12] = [0xA0, 0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0, 0x01, 0x23, 0x45];
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    info!("Starting bpsk_tx");
+
+    let board = BoardNucleoL476Rg::init(&spawner).await;
+    let mut lr2021 = board.lr2021;
+    let mut irq = board.irq;
+
+    // Initialize transceiver for BPSK: 868.13MHz, 0dBm
+    set_rf_auto_path(&mut lr2021, 868_130_000, RxBoost::Off).await.expect("SetRF");
+    lr2021.calib_fe(&[]).await.expect("Front-End calibration");
+
+    match lr2021.get_status().await {
+        Ok((status, intr)) => info!("Calibration Done: {} | {}", status, intr),
+        Err(e) => warn!("Calibration Failed: {}", e),
+    }
+
+    lr2021.set_pa_lf(PaLfMode::LfPaFsm, 6, 7).await.expect("Set PA LF");
+    lr2021.set_tx_params(0, RampTime::Ramp8u).await.expect("SetTxParams");
+
+    let mut bitrate = 100;
+    lr2021.set_packet_type(PacketType::Bpsk).await.expect("SetPktType");
+    set_bpsk_modulation(&mut lr2021, &BpskModulation::dbpsk(bitrate)).await.expect("SetBpskModulation");
+
+    // Set DIO7 as IRQ for TX Done
+    lr2021.set_dio_irq(DioNum::Dio7, Intr::new(IRQ_MASK_TX_DONE)).await.expect("Setting DIO7 as IRQ");
+
+    let mut button_press = BoardNucleoL476Rg::get_button_evt();
+    BoardNucleoL476Rg::led_red_set(LedMode::BlinkSlow);
+
+    let ramp = BpskRamp::default();
+    loop {
+        match select(button_press.changed(), irq.wait_for_rising_edge()).await {
+            Either::First(press) => {
+                match press {
+                    ButtonPressKind::Short => {
+                        info!("[TX] Sending {:02x} at {}b/s", PAYLOAD, bitrate);
+                        bpsk_tx(&mut lr2021, &PAYLOAD, &ramp).await.expect("BpskTx");
+                    }
+                    ButtonPressKind::Double => {
+                        bitrate = if bitrate == 100 {600} else {100};
+                        change_bitrate(&mut lr2021, bitrate).await;
+                    }
+                    n => warn!("{} not implemented !", n),
+                }
+            }
+            Either::Second(_) => {
+                let intr = lr2021.get_and_clear_irq().await.expect("GetIrqs");
+                if intr.tx_done() {
+                    BoardNucleoL476Rg::led_red_set(LedMode::Flash);
+                    info!("[TX] Done");
+                }
+            }
+        }
+    }
+}
+
+async fn change_bitrate(lr2021: &mut Lr2021Stm32, bitrate: u32) {
+    lr2021.set_chip_mode(ChipMode::Fs).await.expect("SetFs");
+    set_bpsk_modulation(lr2021, &BpskModulation::dbpsk(bitrate)).await.expect("SetBpskModulation");
+    info!("Bitrate set to {}b/s", bitrate);
+}
@@ -13,10 +13,10 @@ use {defmt_rtt as _, panic_probe as _};
 use embassy_executor::Spawner;
 use embassy_futures::select::{select, Either};
 
-use lr2021_apps::board::{BoardNucleoL476Rg, BoardRole, ButtonPressKind, LedMode, Lr2021Stm32};
+use lr2021_apps::{board::{BoardNucleoL476Rg, BoardRole, ButtonPressKind, LedMode, Lr2021Stm32}, pkt_utils::{log_rx_stats, take_rx_stats}, radio_utils::set_rf_auto_path};
 use lr2021::{
     flrc::*,
-    radio::{FallbackMode, PaLfMode, PacketType, RampTime, RxBoost, RxPath},
+    radio::{FallbackMode, PaLfMode, PacketType, RampTime, RxBoost},
     status::{Intr, IRQ_MASK_RX_DONE, IRQ_MASK_TX_DONE},
     system::{ChipMode, DioNum}, PulseShape
 };
@@ -65,10 +65,8 @@ async fn main(spawner: Spawner) {
     let mut sw_sel = SwSel::Sw1;
 
     // Initialize transceiver for LoRa communication
-    lr2021.set_rf(900_000_000).await.expect("Setting RF to 900MHz");
-    lr2021.set_rx_path(RxPath::LfPath, RxBoost::Off).await.expect("Setting RX path to LF");
-    // lr2021.set_rf(2_400_000_000).await.expect("Setting RF to 2.4GHz");
-    // lr2021.set_rx_path(RxPath::HfPath, 0).await.expect("Setting RX path to HF");
+    set_rf_auto_path(&mut lr2021, 900_000_000, RxBoost::Off).await.expect("Setting RF to 900MHz");
+    // set_rf_auto_path(&mut lr2021, 2_400_000_000, RxBoost::Off).await.expect("Setting RF to 2.4GHz");
     lr2021.calib_fe(&[]).await.expect("Front-End calibration");
     match lr2021.get_status().await {
         Ok((status, intr)) => info!("Calibration Done: {} | {}", status, intr),
@@ -146,13 +144,8 @@ async fn main(spawner: Spawner) {
 }
 
 async fn show_and_clear_rx_stats(lr2021: &mut Lr2021Stm32) {
-    let stats = lr2021.get_flrc_rx_stats().await.expect("RX stats");
-    info!("[RX] Clearing stats | RX={}, CRC Err={}, LenErr={}",
-        stats.pkt_rx(),
-        stats.crc_error(),
-        stats.len_error(),
-    );
-    lr2021.clear_rx_stats().await.unwrap();
+    let stats = take_rx_stats::<FlrcRxStatsRsp>(lr2021).await.expect("RX stats");
+    log_rx_stats(&stats);
 }
 
 async fn send_pkt(lr2021: &mut Lr2021Stm32, pkt_id: &mut u8, data: &mut [u8]) {
@@ -23,10 +23,16 @@ use embassy_executor::Spawner;
 use embassy_futures::select::{select3, Either3};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
 
-use lr2021_apps::board::{BoardNucleoL476Rg, BoardRole, ButtonPressKind, LedMode, Lr2021Stm32};
+use lr2021_apps::{
+    board::{BoardNucleoL476Rg, BoardRole, ButtonPressKind, LedMode, Lr2021Stm32},
+    fsk_utils::{set_fsk_packet_params, FskPacketParams},
+    pkt_utils::{log_rx_stats, receive_packet, take_rx_stats},
+    radio_utils::{init_radio, RadioInitConfig},
+    uart_command::{dispatch, CmdLine},
+};
 use lr2021::{
-    fsk::{AddrComp, BitOrder, Crc, FskPktFormat, PblLenDetect, PldLenUnit},
-    radio::{PacketType, RampTime, RxBoost, RxPath},
+    fsk::{BitOrder, GetFskRxStatsRsp},
+    radio::PacketType,
     status::{Intr, IRQ_MASK_RX_DONE, IRQ_MASK_TX_DONE},
     system::{ChipMode, DioNum}, PulseShape, RxBw
 };
@@ -39,6 +45,13 @@ enum UartCmd {
 }
 type SignalCmd = Signal<CriticalSectionRawMutex, UartCmd>;
 static CMD : SignalCmd = Signal::new();
+/// UART commands: one letter per command
+const UART_CMDS: [(u8, UartCmd); 4] = [
+    (b's', UartCmd::SwitchTxRx),
+    (b't', UartCmd::StartTx),
+    (b'a', UartCmd::ToggleAuto),
+    (b'h', UartCmd::ChangeModIdx),
+];
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
@@ -54,11 +67,8 @@ async fn main(spawner: Spawner) {
     let mut pkt_id = 0_u8;
     let mut fdev = 62500;
 
-    // Initialize transceiver for FSK communication
-    // 901MHz, 0dbM, SF5 BW1000, CR 4/5
-    lr2021.set_rf(901_000_000).await.expect("Setting RF to 901MHz");
-    lr2021.set_rx_path(RxPath::LfPath, RxBoost::Off).await.expect("Setting RX path to LF");
-    lr2021.calib_fe(&[]).await.expect("Front-End calibration");
+    // Initialize transceiver for FSK communication: 901MHz, 0dBm
+    init_radio(&mut lr2021, &RadioInitConfig::new(901_000_000)).await.expect("Radio init");
 
     match lr2021.get_status().await {
         Ok((status, intr)) => info!("Calibration Done: {} | {}", status, intr),
@@ -68,8 +78,8 @@ async fn main(spawner: Spawner) {
     lr2021.set_packet_type(PacketType::FskLegacy).await.expect("SetPktType");
     lr2021.set_fsk_modulation(250_000, PulseShape::Bt0p5, RxBw::Bw444, fdev).await.expect("SetFskModulation");
     lr2021.set_fsk_syncword(0xCD05DEAD, BitOrder::LsbFirst, 32).await.expect("SetSyncword");
-    lr2021.set_fsk_packet(8, PblLenDetect::None, false, PldLenUnit::Bytes, AddrComp::Off, FskPktFormat::Variable8bit, 10, Crc::Crc2Byte, true).await.expect("SetPkt");
-    lr2021.set_tx_params(0, RampTime::Ramp8u).await.expect("Setting TX parameters");
+    let pkt_params = FskPacketParams {pld_len: PLD_SIZE, ..Default::default()};
+    set_fsk_packet_params(&mut lr2021, &pkt_params).await.expect("SetPkt");
 
     // Start RX continuous
     match lr2021.set_rx(0xFFFFFFFF, true).await {
@@ -130,7 +140,7 @@ async fn main(spawner: Spawner) {
 
                 } else if !intr.crc_error() {
                     BoardNucleoL476Rg::led_green_set(LedMode::Flash);
-                    show_rx_pkt(&mut lr2021).await;
+                    show_rx_pkt(&mut lr2021, intr).await;
                 } else {
                     warn!("CRC Error");
                     lr2021.clear_rx_fifo().await.unwrap();
@@ -165,14 +175,8 @@ async fn main(spawner: Spawner) {
 }
 
 async fn show_and_clear_rx_stats(lr2021: &mut Lr2021Stm32) {
-    let stats = lr2021.get_fsk_rx_stats().await.expect("RX stats");
-    info!("[RX] Clearing stats | RX={}, CRC Err={}, LenErr={} | Detect={}, SyncFail={}",
-        stats.pkt_rx(),
-        stats.crc_error(),
-        stats.len_error(),
-        stats.pbl_det(),
-        stats.sync_fail(),
-    );
+    let stats = take_rx_stats::<GetFskRxStatsRsp>(lr2021).await.expect("RX stats");
+    log_rx_stats(&stats);
 }
 
 async fn send_pkt(lr2021: &mut Lr2021Stm32, pkt_id: &mut u8) {
@@ -201,15 +205,14 @@ async fn switch_mode(lr2021: &mut Lr2021Stm32, is_rx: bool) {
     }
 }
 
-async fn show_rx_pkt(lr2021: &mut Lr2021Stm32) {
-    let pkt_len = lr2021.get_rx_pkt_len().await.expect("RX Fifo level") as usize;
-    let status = lr2021.get_fsk_packet_status().await.expect("RX status");
-    lr2021.rd_rx_fifo(pkt_len).await.expect("RX FIFO Read");
-    let lqi = status.lqi();
+async fn show_rx_pkt(lr2021: &mut Lr2021Stm32, intr: Intr) {
+    let mut data = [0u8; 256];
+    let (pkt_len, info) = receive_packet(lr2021, intr, &mut data).await.expect("RX Packet");
+    let lqi = info.lqi.unwrap_or(0);
     let lqi_frac = (lqi&3) * 25;
-    info!("[RX] Payload = {:02x} | RSSI=-{}dBm, LQI={}.{:02}",
-        lr2021.buffer()[..pkt_len],
-        status.rssi_avg()>>1,
+    info!("[RX] Payload = {:02x} | RSSI={}dBm, LQI={}.{:02}",
+        data[..pkt_len],
+        info.rssi_avg_dbm,
         lqi>>2, lqi_frac
     );
 }
@@ -220,14 +223,9 @@ pub async fn handle_uart(mut uart: Uart<'static, Async>, sig_cmd: &'static Signa
         // Wait for a command
         let mut buffer = [0u8;8];
         uart.read_until_idle(&mut buffer).await.ok();
-        // Parsing: either R[min]-[max] or S[step]
-        let cmd = match buffer[0] {
-            b'S' | b's' => UartCmd::SwitchTxRx,
-            b'T' | b't' => UartCmd::StartTx,
-            b'A' | b'a' => UartCmd::ToggleAuto,
-            b'H' | b'h' => UartCmd::ChangeModIdx,
-            _ => UartCmd::Invalid,
-        };
+        let cmd = CmdLine::parse(&buffer)
+            .and_then(|line| dispatch(&UART_CMDS, &line))
+            .unwrap_or(UartCmd::Invalid);
         // info!("[UART] Command = {}", cmd);
         uart.write(&buffer[0..1]).await.ok();
         sig_cmd.signal(cmd);
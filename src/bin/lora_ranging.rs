@@ -9,7 +9,7 @@
 
 use defmt::*;
 use embassy_stm32::{mode::Async, usart::Uart};
-use embassy_time::Timer;
+use embassy_time::{Duration, Timer};
 use {defmt_rtt as _, panic_probe as _};
 
 use core::fmt::Write;
@@ -18,11 +18,18 @@ use heapless::String;
 use embassy_executor::Spawner;
 use embassy_futures::select::{select, Either};
 
-use lr2021_apps::board::{BoardNucleoL476Rg, ButtonPressKind, LedMode, Lr2021Stm32};
+use lr2021_apps::{
+    board::{BoardNucleoL476Rg, ButtonPressKind, LedMode, Lr2021Stm32},
+    intr_utils::IntrFlag,
+    radio_utils::{calib_fe_range, set_rx_continuous, set_rx_for},
+    ranging_utils::{
+        configure_ranging, get_ranging_meas, get_ranging_stats, ranging_hop, setup_ranging,
+        FreqHopPlan, RangingConfig, RangingDelayCal, RangingRole,
+    },
+};
 use lr2021::{
     lora::{LoraBw, LoraModulationParams, Sf},
-    radio::{PacketType, RampTime, RxBoost, RxPath},
-    status::{Intr, IRQ_MASK_RNG_EXCH_VLD, IRQ_MASK_RNG_REQ_DIS, IRQ_MASK_RNG_RESP_DONE, IRQ_MASK_RNG_TIMEOUT, IRQ_MASK_TIMEOUT},
+    radio::{RampTime, RxBoost, RxPath},
     system::{ChipMode, DioNum}
 };
 
@@ -68,8 +75,8 @@ struct State {
     mode : RngMode,
     /// Board role: initiator or responder
     initiator : bool,
-    /// RF channel
-    rf: u32,
+    /// RF channel hopping plan
+    plan: FreqHopPlan,
     /// RSSI offset to apply on ranging result
     rssi_offset: i16,
 }
@@ -82,7 +89,7 @@ impl State {
             to_cnt: 0,
             mode: RngMode::Burst,
             initiator: false,
-            rf: RF_START,
+            plan: FreqHopPlan::new(RF_START, RF_STOP, RF_STEP),
             rssi_offset,
         }
     }
@@ -91,7 +98,7 @@ impl State {
     pub fn abort(&mut self) {
         self.pkt_rem = 0;
         self.to_cnt = 0;
-        self.rf = RF_START;
+        self.plan.reset();
     }
 
     /// Change role
@@ -106,19 +113,6 @@ impl State {
         self.mode = self.mode.next();
         info!("Mode {}", self.mode);
     }
-
-    /// Increment RF
-    /// Return true when wrapping
-    pub fn hop(&mut self) -> bool {
-        self.rf += RF_STEP;
-        if self.rf > RF_STOP {
-            self.rf = RF_START;
-            true
-        } else {
-            false
-        }
-    }
-
 }
 
 #[embassy_executor::main]
@@ -134,7 +128,8 @@ async fn main(spawner: Spawner) {
     // Initialize transceiver for LoRa communication
     lr2021.set_rf(RF_START).await.expect("SetRF");
     lr2021.set_rx_path(RxPath::LfPath, RxBoost::Max).await.expect("Setting RX path to LF");
-    lr2021.calib_fe(&[]).await.expect("Front-End calibration");
+    // Calibrate the whole hopping band
+    calib_fe_range(&mut lr2021, RF_START, RF_STOP).await.expect("Front-End calibration");
     lr2021.set_tx_params(22, RampTime::Ramp8u).await.expect("SetTxParams");
 
     match lr2021.get_status().await {
@@ -144,23 +139,23 @@ async fn main(spawner: Spawner) {
 
     let modulation = LoraModulationParams::basic(SF, BW);
 
-    lr2021.set_packet_type(PacketType::Ranging).await.expect("Setting packet type");
-    lr2021.patch_ranging_rf().await.expect("PatchRangingRf");
-    lr2021.set_ranging_modulation(&modulation, false).await.expect("SetModulation");
-    lr2021.set_ranging_dev_addr(ADDR_RSP, None).await.expect("SetDevAddr"); // Default role is responder
-    lr2021.set_ranging_req_addr(ADDR_RSP).await.expect("SetReqAddr");
+    // Default role is responder
+    let mut rng_cfg = RangingConfig {dev_addr: ADDR_RSP, req_addr: ADDR_RSP, addr_len: None, role: RangingRole::Responder};
+    setup_ranging(&mut lr2021, &modulation, &rng_cfg).await.expect("SetupRanging");
     lr2021.set_ranging_params(true, false, 12).await.expect("SetRangingParams");
-    let delay = lr2021.get_ranging_base_delay(&modulation);
-    lr2021.set_ranging_txrx_delay(delay-10).await.expect("SetRangingDelay"); // Value depends on SF, BW and PCB
+    // Delay offset depends on SF, BW and PCB: use default value until calibrated
+    let delay_cal = RangingDelayCal::new();
+    delay_cal.apply(&mut lr2021, SF, BW).await.expect("SetRangingDelay");
 
     // Start RX continuous
-    match lr2021.set_rx(0xFFFFFFFF, true).await {
+    match set_rx_continuous(&mut lr2021).await {
         Ok(_) => info!("[RX] Searching Preamble"),
         Err(e) => error!("Fail while set_rx() : {}", e),
     }
 
     // Set DIO7 as IRQ for RX Done
-    lr2021.set_dio_irq(DioNum::Dio7, Intr::new(IRQ_MASK_RNG_EXCH_VLD|IRQ_MASK_RNG_RESP_DONE|IRQ_MASK_RNG_REQ_DIS|IRQ_MASK_TIMEOUT|IRQ_MASK_RNG_TIMEOUT)).await.expect("Setting DIO7 as IRQ");
+    let irq_mask = IntrFlag::RngExchVld | IntrFlag::RngRespDone | IntrFlag::RngReqDis | IntrFlag::Timeout | IntrFlag::RngTimeout;
+    lr2021.set_dio_irq(DioNum::Dio7, irq_mask).await.expect("Setting DIO7 as IRQ");
 
     // Wait for a button press for actions
     let mut button_press = BoardNucleoL476Rg::get_button_evt();
@@ -194,12 +189,13 @@ async fn main(spawner: Spawner) {
                             show_stats(&mut lr2021, state.initiator).await;
                         }
                     }
+                    ButtonPressKind::LongRepeat => {}
                     // Long press: switch role TX/RX
                     ButtonPressKind::Long => {
                         state.toggle_role();
-                        let addr = if state.initiator {ADDR_INI} else {ADDR_RSP} ;
-                        lr2021.set_ranging_modulation(&modulation, state.initiator).await.expect("SetModulation");
-                        lr2021.set_ranging_dev_addr(addr, None).await.expect("SetDevAddr");
+                        rng_cfg.role.toggle();
+                        rng_cfg.dev_addr = if state.initiator {ADDR_INI} else {ADDR_RSP};
+                        configure_ranging(&mut lr2021, &modulation, &rng_cfg).await.expect("ConfigureRanging");
                         switch_mode(&mut lr2021, state.initiator).await;
                     }
                 }
@@ -235,10 +231,10 @@ async fn main(spawner: Spawner) {
                         // After too many timeout just go back to initial RF and start continuous RX
                         if state.to_cnt == 4 && !state.mode.is_tracking() {
                             state.abort();
-                            info!("Too many Timeout, back to {}MHz", state.rf/1000000);
-                            lr2021.set_rf_ranging(state.rf).await.expect("SetRF");
+                            info!("Too many Timeout, back to {}MHz", state.plan.current()/1000000);
+                            lr2021.set_rf_ranging(state.plan.current()).await.expect("SetRF");
                             if !state.initiator {
-                                lr2021.set_rx(0xFFFFFFFF, true).await.expect("SetRx");
+                                set_rx_continuous(&mut lr2021).await.expect("SetRx");
                             }
                         }
                         // On initiator side send a packet after 50ms is counter is still not null
@@ -268,29 +264,25 @@ async fn main(spawner: Spawner) {
 }
 
 async fn hop_rf(lr2021: &mut Lr2021Stm32, state : &mut State) {
-    state.hop();
-    if !state.initiator {
-        lr2021.set_chip_mode(ChipMode::Fs).await.expect("SetFs");
-    }
-    lr2021.set_rf_ranging(state.rf).await.expect("SetRF");
-    // info!("Setting RF to {}MHz", state.rf/1000000);
+    ranging_hop(lr2021, &mut state.plan, state.initiator).await.expect("RangingHop");
+    // info!("Setting RF to {}MHz", state.plan.current()/1000000);
     if !state.initiator {
-        // TX send a packet roughly every 50ms, so set timeout RX after ~70us
+        // TX send a packet roughly every 50ms, so set timeout RX after ~64ms
         // Take extra margin to handle delay due to clock not being synchronized and debug print adding some delays
         // This allows to keep hopping and hopefully stays on the same RF as the initiator
-        lr2021.set_rx(2100, true).await.expect("SetRx");
+        set_rx_for(lr2021, Duration::from_millis(64)).await.expect("SetRx");
     }
 }
 
 async fn show_stats(lr2021: &mut Lr2021Stm32, is_initiator: bool) {
-    let stats = lr2021.get_ranging_stats().await.expect("RX stats");
+    let stats = get_ranging_stats(lr2021).await.expect("RX stats");
     lr2021.clear_rx_stats().await.expect("Clearing stats");
     if is_initiator {
-        info!("[INI] Exchange={}/{}, Timeout={}",
-            stats.exchange_valid(), stats.request_valid(), stats.timeout());
+        info!("[INI] Exchange={}/{} ({}%), Timeout={}",
+            stats.exchange_valid, stats.request_valid, stats.success_rate(), stats.timeout);
     } else {
         info!("[RSP] Response={}, Discard={}",
-            stats.response_done(), stats.request_discarded());
+            stats.response_done, stats.request_discarded);
     }
 }
 
@@ -310,24 +302,22 @@ async fn switch_mode(lr2021: &mut Lr2021Stm32, is_initiator: bool) {
         BoardNucleoL476Rg::led_red_set(LedMode::Off);
         BoardNucleoL476Rg::led_green_set(LedMode::Off);
     } else {
-        lr2021.set_rx(0xFFFFFFFF, true).await.expect("SetRx");
+        set_rx_continuous(lr2021).await.expect("SetRx");
         BoardNucleoL476Rg::led_red_set(LedMode::Off);
         BoardNucleoL476Rg::led_green_set(LedMode::BlinkSlow);
     }
 }
 
 async fn show_ranging_meas(lr2021: &mut Lr2021Stm32, uart: &mut Uart<'static, Async>, state: &State) {
-    let result = lr2021.get_ranging_ext_result().await.expect("GetRangingResult");
-    let rttof = (result.rng1() + result.rng2()) / 2;
-    let doppler = result.rng2() - result.rng1();
-    let dist_cm = (rttof * 150 * 100) / 4096; // Bandwidth 1MHz
-    let rssi = state.rssi_offset + result.rssi1() as i16;
-    // speed_kmh = (doppler - bias) * BW/(1<<sf) * c/2*3.6 / 4096 / RF
-    info!("[RX] RF={}MHz {} | Dist = {} (raw = {}/{}), RSSI = {}dBm, Doppler = {}",
-        state.rf/1000000, state.pkt_rem, dist_cm, result.rng1(), result.rng2(), rssi, doppler
+    let meas = get_ranging_meas(lr2021).await.expect("GetRangingResult");
+    info!("[RX] RF={}MHz {} | Dist = {}cm (raw = {}/{} -> {}), RSSI = {}dBm, Doppler = {} ({}km/h)",
+        state.plan.current()/1000000, state.pkt_rem,
+        meas.distance_cm(BW), meas.rng1, meas.rng2, meas.rttof(),
+        meas.rssi1_dbm(state.rssi_offset),
+        meas.doppler(), meas.speed_kmh(BW, SF, state.plan.current())
     );
     // Uart:
     let mut s: String<32> = String::new();
-    core::write!(&mut s, "{}/{}|{}\r\n", result.rng1(), result.rng2(), result.rssi1()).ok();
+    core::write!(&mut s, "{}/{}|{}\r\n", meas.rng1, meas.rng2, meas.rssi1).ok();
     uart.write(s.as_bytes()).await.ok();
 }
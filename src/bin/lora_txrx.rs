@@ -13,9 +13,9 @@ use {defmt_rtt as _, panic_probe as _};
 use embassy_executor::Spawner;
 use embassy_futures::select::{select, Either};
 
-use lr2021_apps::board::{BoardNucleoL476Rg, BoardRole, ButtonPressKind, LedMode, Lr2021Stm32};
+use lr2021_apps::{board::{BoardNucleoL476Rg, BoardRole, ButtonPressKind, LedMode, Lr2021Stm32}, pkt_utils::{log_rx_stats, take_rx_stats}};
 use lr2021::{
-    lora::{LoraBw, LoraModulationParams, LoraPacketParams, Sf},
+    lora::{GetLoraRxStatsRsp, LoraBw, LoraModulationParams, LoraPacketParams, Sf},
     radio::{PacketType, RampTime, RxBoost, RxPath},
     status::{Intr, IRQ_MASK_RX_DONE},
     system::{ChipMode, DioNum}
@@ -97,13 +97,8 @@ async fn main(spawner: Spawner) {
 }
 
 async fn show_and_clear_rx_stats(lr2021: &mut Lr2021Stm32) {
-    let stats = lr2021.get_lora_rx_stats().await.expect("RX stats");
-    info!("[RX] Clearing stats | RX={}, CRC Err={}, HdrErr={}, FalseSync={}",
-        stats.pkt_rx(),
-        stats.crc_error(),
-        stats.header_error(),
-        stats.false_sync(),
-    );
+    let stats = take_rx_stats::<GetLoraRxStatsRsp>(lr2021).await.expect("RX stats");
+    log_rx_stats(&stats);
 }
 
 async fn send_pkt(lr2021: &mut Lr2021Stm32, pkt_id: &mut u8) {
@@ -20,6 +20,7 @@ use {defmt_rtt as _, panic_probe as _};
 
 use lr2021::{BusyAsync, system::DioNum};
 use lr2021_apps::board::*;
+use lr2021_apps::pkt_utils::log_rx_stats;
 use lr2021_loraphy::{Bandwidth, CodingRate, IrqState, Lr2021LoraPhy, PacketParams, RadioKind, RadioMode, SpreadingFactor};
 
 const PLD_SIZE : u8 = 10;
@@ -41,7 +42,7 @@ async fn main(spawner: embassy_executor::Spawner) {
     // Start the tasks
     spawner.spawn(blink(led_red, &LED_RED_MODE)).unwrap();
     spawner.spawn(blink(led_green, &LED_GREEN_MODE)).unwrap();
-    spawner.spawn(user_intf(button, &BUTTON_PRESS)).unwrap();
+    spawner.spawn(user_intf(button, &BUTTON_PRESS, ButtonConfig::default())).unwrap();
     BoardNucleoL476Rg::led_red_set(LedMode::Off);
     BoardNucleoL476Rg::led_green_set(LedMode::BlinkSlow);
 
@@ -132,12 +133,7 @@ async fn main(spawner: embassy_executor::Spawner) {
 
 async fn show_and_clear_rx_stats(lr2021: &mut Lr2021Stm32) {
     let stats = lr2021.driver.get_lora_rx_stats().await.expect("RX stats");
-    info!("[RX] Clearing stats | RX={}, CRC Err={}, HdrErr={}, FalseSync={}",
-        stats.pkt_rx(),
-        stats.crc_error(),
-        stats.header_error(),
-        stats.false_sync(),
-    );
+    log_rx_stats(&stats);
 }
 
 async fn send_pkt(lr2021: &mut Lr2021Stm32, pkt_id: &mut u8, buffer: &mut [u8]) {
@@ -0,0 +1,96 @@
+#![no_std]
+#![no_main]
+
+//! # LR-FHSS TX demo application
+//!
+//! Send an LR-FHSS frame at 868.1MHz (EU configuration: 3.9kHz grid, 136kHz bandwidth, CR 1/3)
+//! Short press send a packet of incrementing byte, double press select the next hopping sequence
+//! The red led flash at the end of each transmission and the number of hops is logged
+
+use defmt::*;
+use {defmt_rtt as _, panic_probe as _};
+
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
+
+use lr2021_apps::{
+    board::{BoardNucleoL476Rg, ButtonPressKind, LedMode},
+    intr_utils::{IntrExt, IntrFlag},
+    lrfhss_utils::{lrfhss_tx, set_lrfhss_modulation, LrFhssParams, LRFHSS_SYNCWORD},
+    radio_utils::set_rf_auto_path,
+};
+use lr2021::{
+    radio::{PaLfMode, RampTime, RxBoost},
+    status::{Intr, IRQ_MASK_FHSS, IRQ_MASK_TX_DONE},
+    system::DioNum,
+};
+
+const RF: u32 = 868_100_000;
+const PLD_SIZE: usize = 10;
+/// Number of hopping sequences with the 3.9kHz grid
+const NB_SEQUENCE: u16 = 384;
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    info!("Starting lrfhss_tx");
+
+    let board = BoardNucleoL476Rg::init(&spawner).await;
+    let mut lr2021 = board.lr2021;
+    let mut irq = board.irq;
+
+    // Initialize transceiver for LR-FHSS at 868.1MHz, 14dBm
+    set_rf_auto_path(&mut lr2021, RF, RxBoost::Off).await.expect("SetRF");
+    lr2021.calib_fe(&[]).await.expect("Front-End calibration");
+
+    match lr2021.get_status().await {
+        Ok((status, intr)) => info!("Calibration Done: {} | {}", status, intr),
+        Err(e) => warn!("Calibration Failed: {}", e),
+    }
+
+    lr2021.set_pa_lf(PaLfMode::LfPaFsm, 6, 7).await.expect("Set PA LF");
+    lr2021.set_tx_params(14, RampTime::Ramp8u).await.expect("SetTxParams");
+    set_lrfhss_modulation(&mut lr2021, LRFHSS_SYNCWORD).await.expect("SetLrFhssModulation");
+
+    // Set DIO7 as IRQ for TX Done and each hop
+    lr2021.set_dio_irq(DioNum::Dio7, Intr::new(IRQ_MASK_TX_DONE|IRQ_MASK_FHSS)).await.expect("Setting DIO7 as IRQ");
+
+    let mut button_press = BoardNucleoL476Rg::get_button_evt();
+    BoardNucleoL476Rg::led_red_set(LedMode::BlinkSlow);
+
+    let mut params = LrFhssParams::default();
+    let mut pkt_id = 0_u8;
+    let mut nb_hop = 0_u16;
+    loop {
+        match select(button_press.changed(), irq.wait_for_rising_edge()).await {
+            Either::First(press) => {
+                match press {
+                    ButtonPressKind::Short => {
+                        info!("[TX] Sending packet {} (sequence {})", pkt_id, params.sequence);
+                        let mut payload = [0u8; PLD_SIZE];
+                        for (i,d) in payload.iter_mut().enumerate() {
+                            *d = pkt_id.wrapping_add(i as u8);
+                        }
+                        nb_hop = 0;
+                        lrfhss_tx(&mut lr2021, &params, &payload).await.expect("LrFhssTx");
+                        pkt_id = pkt_id.wrapping_add(1);
+                    }
+                    ButtonPressKind::Double => {
+                        params = params.with_sequence((params.sequence + 1) % NB_SEQUENCE);
+                        info!("Hopping sequence {}", params.sequence);
+                    }
+                    n => warn!("{} not implemented !", n),
+                }
+            }
+            Either::Second(_) => {
+                let intr = lr2021.get_and_clear_irq().await.expect("GetIrqs");
+                if intr.contains(IntrFlag::Fhss) {
+                    nb_hop += 1;
+                }
+                if intr.tx_done() {
+                    BoardNucleoL476Rg::led_red_set(LedMode::Flash);
+                    info!("[TX] Done after {} hops", nb_hop);
+                }
+            }
+        }
+    }
+}
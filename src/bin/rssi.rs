@@ -9,12 +9,12 @@ use {defmt_rtt as _, panic_probe as _};
 
 use embassy_executor::Spawner;
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
-use embassy_time::Timer;
+use embassy_time::{Duration, Timer};
 
 use core::fmt::Write;
 use heapless::String;
 
-use lr2021_apps::board::{BoardNucleoL476Rg, LedMode};
+use lr2021_apps::{board::{BoardNucleoL476Rg, LedMode}, radio_utils::{rx_bw_from_khz, scan_spectrum, set_rx_gain_manual, RX_GAIN_MAX}, uart_command::CmdLine};
 use lr2021::{
     radio::{PacketType, RxBoost, RxPath}, PulseShape, RxBw
 };
@@ -23,6 +23,12 @@ const RF_MIN : u32 =   400_000_000;
 const RF_MAX : u32 = 1_100_000_000;
 const RF_STEP: u32 =       250_000;
 const RX_BW  : RxBw =  RxBw::Bw256;
+/// Number of RSSI measurement done in one call to scan_spectrum
+const SCAN_CHUNK: usize = 32;
+/// Settling time after each retune before measuring the RSSI:
+/// covers the PLL lock and the RSSI averaging of the previous frequency being flushed.
+/// Close to the time the demo used to spend sending one measurement on the UART between retune and measure.
+const DWELL: Duration = Duration::from_micros(250);
 
 pub type SignalData = Signal<CriticalSectionRawMutex, (u32,u16)>;
 static DATA : SignalData = Signal::new();
@@ -61,7 +67,7 @@ async fn main(spawner: Spawner) {
     }
 
     // Setup radio to max gain (saturation unlikely in ADS-B and AGC might induce packet loss)
-    lr2021.set_rx_gain(13).await.ok();
+    set_rx_gain_manual(&mut lr2021, RX_GAIN_MAX).await.ok();
     lr2021.set_rx(0xFFFFFFFF, true).await.ok();
     BoardNucleoL476Rg::led_green_set(LedMode::BlinkSlow);
 
@@ -71,13 +77,21 @@ async fn main(spawner: Spawner) {
     let mut rf_min  = RF_MIN;
     let mut rf_max  = RF_MAX;
     let mut rf_step = RF_STEP;
+    let mut samples = [0u16; SCAN_CHUNK];
     loop {
-        let rssi = lr2021.get_rssi_avg(16).await.expect("RssiAvg");
-        // Wait for the UART to be ready
-        while DATA.signaled() {
-            Timer::after_micros(10).await;
+        let nb = scan_spectrum(&mut lr2021, rf, rf_max, rf_step, DWELL, &mut samples).await.expect("ScanSpectrum");
+        for &rssi in samples[..nb].iter() {
+            // Stop forwarding on a configuration change to apply it immediately
+            if CFG.signaled() {
+                break;
+            }
+            // Wait for the UART to be ready
+            while DATA.signaled() {
+                Timer::after_micros(10).await;
+            }
+            DATA.signal((rf, rssi));
+            rf += rf_step;
         }
-        DATA.signal((rf, rssi));
         // Handle change in configuration
         if let Some((min,max,step)) = CFG.try_take() {
             info!("Config changed to {}:{}:{} !", min, max, step);
@@ -87,9 +101,9 @@ async fn main(spawner: Spawner) {
             // Step in kHz
             if (1..1000).contains(&step) {
                 // On Step change ensure we start back at RF MIN
-                rf = rf_max;
+                rf = rf_min;
                 rf_step = step as u32 * 1_000;
-                let rf_bw = khz_to_bw(step);
+                let rf_bw = rx_bw_from_khz(step as u32);
                 // Change Bandwidth
                 lr2021.set_chip_mode(lr2021::system::ChipMode::Fs).await.ok();
                 lr2021.set_fsk_modulation(rf_step, PulseShape::Bt0p5, rf_bw, rf_step>>3).await.expect("SetFskModulation");
@@ -99,14 +113,12 @@ async fn main(spawner: Spawner) {
                 info!("[UART] Range set to {}-{} MHz", min, max);
             }
         }
-        // Update current RF
-        rf += rf_step;
+        // Wrap back to start of the range
         if rf > rf_max {
             info!("Wrapping !");
             BoardNucleoL476Rg::led_red_set(LedMode::Flash);
             rf = rf_min;
         }
-        lr2021.set_rf(rf).await.expect("SetRF");
     }
 }
 
@@ -131,118 +143,19 @@ pub async fn parse_uart(mut uart: UartRx<'static, Async>, cfg: &'static SignalCf
         let mut buffer = [0u8;32];
         uart.read_until_idle(&mut buffer).await.ok();
         // Parsing: either R[min]-[max] or S[step]
-        match buffer[0] {
-            b'R' | b'r' => {
-                let (min,offset) = parse_num(&buffer[1..]);
-                let (max,_) = parse_num(&buffer[1+offset..]);
+        let Some(line) = CmdLine::parse(&buffer) else {continue};
+        match line.letter {
+            b'r' => {
+                let min = line.arg(0).unwrap_or(0) as u16;
+                let max = line.arg(1).unwrap_or(0) as u16;
                 cfg.signal((min, max,0));
                 info!("[UART] Changing range to : {}MHz to {}MHz", min, max);
             }
-            b'S' | b's' => {
-                let (step,_) = parse_num(&buffer[1..]);
+            b's' => {
+                let step = line.arg(0).unwrap_or(0) as u16;
                 cfg.signal((0, 0, step));
             }
             _ => {}
         }
     }
 }
-
-fn parse_num(buffer: &[u8]) -> (u16,usize) {
-    let mut v = 0u16;
-    let mut idx = 0;
-    for c in buffer {
-        idx += 1;
-        match c {
-            48..=57 => v = 10*v + (c-48) as u16,
-            b'_' => {}
-            _ => break,
-        }
-    }
-    (v,idx)
-}
-
-fn khz_to_bw(value: u16) -> RxBw {
-    match value {
-        0..=3=> RxBw::Bw3p5,
-        4 => RxBw::Bw4p2,
-        5 => RxBw::Bw5p2,
-        6 => RxBw::Bw6,
-        7 => RxBw::Bw7p4,
-        8 => RxBw::Bw8,
-        9 => RxBw::Bw9p6,
-        10 => RxBw::Bw10,
-        11 => RxBw::Bw11,
-        12 => RxBw::Bw12,
-        13 => RxBw::Bw13,
-        14 => RxBw::Bw14,
-        15 | 16 => RxBw::Bw16,
-        17 => RxBw::Bw17,
-        18|19=> RxBw::Bw19,
-        20 => RxBw::Bw20,
-        21|22=> RxBw::Bw22,
-        23 => RxBw::Bw23,
-        24 => RxBw::Bw24,
-        25..=27 => RxBw::Bw27,
-        28..=29 => RxBw::Bw29,
-        30..=32=> RxBw::Bw32,
-        33=> RxBw::Bw33,
-        34=> RxBw::Bw34,
-        35=> RxBw::Bw35,
-        36..=38=> RxBw::Bw38,
-        39..=41=> RxBw::Bw41,
-        42..=44=> RxBw::Bw44,
-        45|46=> RxBw::Bw46,
-        47|48=> RxBw::Bw48,
-        49..=55=> RxBw::Bw55,
-        56..=59=> RxBw::Bw59,
-        60..=64=> RxBw::Bw64,
-        65..=66=> RxBw::Bw66,
-        67..=69=> RxBw::Bw69,
-        70|71=> RxBw::Bw71,
-        72..=76=> RxBw::Bw76,
-        77..=83=> RxBw::Bw83,
-        84..=89=> RxBw::Bw89,
-        90..=92=> RxBw::Bw92,
-        93..=96=> RxBw::Bw96,
-        97..=111=> RxBw::Bw111,
-        112..=119=> RxBw::Bw119,
-        120..=128=> RxBw::Bw128,
-        129..=133=> RxBw::Bw133,
-        134..=138=> RxBw::Bw138,
-        139..=142=> RxBw::Bw142,
-        143..=153=> RxBw::Bw153,
-        154..=166=> RxBw::Bw166,
-        167..=178=> RxBw::Bw178,
-        179..=185=> RxBw::Bw185,
-        186..=192=> RxBw::Bw192,
-        193..=222=> RxBw::Bw222,
-        223..=238=> RxBw::Bw238,
-        239..=256=> RxBw::Bw256,
-        257..=266=> RxBw::Bw266,
-        267..=277=> RxBw::Bw277,
-        278..=285=> RxBw::Bw285,
-        286..=307=> RxBw::Bw307,
-        308..=333=> RxBw::Bw333,
-        334..=357=> RxBw::Bw357,
-        358..=370=> RxBw::Bw370,
-        371..=384=> RxBw::Bw384,
-        385..=444=> RxBw::Bw444,
-        445..=476=> RxBw::Bw476,
-        477..=512=> RxBw::Bw512,
-        513..=533=> RxBw::Bw533,
-        534..=555=> RxBw::Bw555,
-        556..=571=> RxBw::Bw571,
-        572..=615=> RxBw::Bw615,
-        616..=666=> RxBw::Bw666,
-        667..=714=> RxBw::Bw714,
-        715..=740=> RxBw::Bw740,
-        741..=769=> RxBw::Bw769,
-        770..=888=> RxBw::Bw888,
-        889..=1111=> RxBw::Bw1111,
-        1112..=1333=> RxBw::Bw1333,
-        1334..=2222=> RxBw::Bw2222,
-        2223..=2666=> RxBw::Bw2666,
-        2667..=2857=> RxBw::Bw2857,
-        _ => RxBw::Bw3076,
-    }
-}
\ No newline at end of file
@@ -17,8 +17,14 @@ use embassy_executor::Spawner;
 use embassy_futures::select::{select3, Either3};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
 
-use lr2021_apps::board::{BoardNucleoL476Rg, BoardRole, ButtonPressKind, LedMode, Lr2021Stm32};
+use lr2021_apps::{
+    board::{BoardNucleoL476Rg, BoardRole, ButtonPressKind, LedMode, Lr2021Stm32},
+    pkt_utils::{take_rx_stats, RxStats},
+    radio_utils::{set_rx_gain_manual, RX_GAIN_MAX},
+    uart_command::{dispatch, CmdLine},
+};
 use lr2021::{
+    ook::OokRxStatsRsp,
     radio::{RampTime, RxBoost, RxPath},
     status::{Intr, IRQ_MASK_RX_DONE, IRQ_MASK_TX_DONE},
     system::{ChipMode, DioNum},
@@ -33,6 +39,12 @@ enum UartCmd {
 }
 type SignalCmd = Signal<CriticalSectionRawMutex, UartCmd>;
 static CMD : SignalCmd = Signal::new();
+/// UART commands: one letter per command
+const UART_CMDS: [(u8, UartCmd); 3] = [
+    (b's', UartCmd::SwitchTxRx),
+    (b't', UartCmd::StartTx),
+    (b'p', UartCmd::PatchToggle),
+];
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
@@ -61,7 +73,7 @@ async fn main(spawner: Spawner) {
     lr2021.set_ook_rts().await.expect("SetPktType");
 
     // Start RX continuous at fixed gain
-    lr2021.set_rx_gain(13).await.expect("SetGain");
+    set_rx_gain_manual(&mut lr2021, RX_GAIN_MAX).await.expect("SetGain");
     match lr2021.set_rx(0xFFFFFFFF, true).await {
         Ok(_) => info!("[RX] Searching Preamble"),
         Err(e) => error!("Fail while set_rx() : {}", e),
@@ -131,10 +143,10 @@ async fn main(spawner: Spawner) {
 }
 
 /// Display number of packet received
-/// No CRC and fixed length so error stats are always null
+/// No CRC and fixed length so error stats are always null: only the packet count is shown
 async fn show_and_clear_rx_stats(lr2021: &mut Lr2021Stm32) {
-    let stats = lr2021.get_ook_rx_stats().await.expect("RX stats");
-    info!("[RX] Clearing stats | RX={}",stats.pkt_rx());
+    let stats = take_rx_stats::<OokRxStatsRsp>(lr2021).await.expect("RX stats");
+    info!("[RX] Clearing stats | RX={}", stats.packets());
 }
 
 async fn send_pkt(lr2021: &mut Lr2021Stm32, pkt_id: &mut u8) {
@@ -183,13 +195,9 @@ pub async fn handle_uart(mut uart: Uart<'static, Async>, sig_cmd: &'static Signa
         // Wait for a command
         let mut buffer = [0u8;8];
         uart.read(&mut buffer).await.ok();
-        // Parsing: either R[min]-[max] or S[step]
-        let cmd = match buffer[0] {
-            b'S' | b's' => UartCmd::SwitchTxRx,
-            b'T' | b't' => UartCmd::StartTx,
-            b'P' | b'p' => UartCmd::PatchToggle,
-            _ => UartCmd::Invalid,
-        };
+        let cmd = CmdLine::parse(&buffer)
+            .and_then(|line| dispatch(&UART_CMDS, &line))
+            .unwrap_or(UartCmd::Invalid);
         info!("[UART] Command = {}", cmd);
         uart.write(&buffer[0..1]).await.ok();
         sig_cmd.signal(cmd);
@@ -0,0 +1,107 @@
+#![no_std]
+#![no_main]
+
+//! # TX test mode demo application
+//!
+//! Configure the board in FSK at 868MHz and allows to start the different TX test modes:
+//! Short press cycle through continuous wave, PRBS9 and infinite preamble
+//! Double press toggle output power between 0 and 14dBm
+//! Long press stop the test and go back to standby
+//!
+//! Red led is on while transmitting
+
+use defmt::*;
+use {defmt_rtt as _, panic_probe as _};
+
+use embassy_executor::Spawner;
+
+use lr2021_apps::{
+    board::{BoardNucleoL476Rg, ButtonPressKind, LedMode, Lr2021Stm32},
+    radio_utils::{stop_test, tx_continuous_wave, tx_infinite_preamble, tx_prbs9},
+};
+use lr2021::{
+    radio::{PaLfMode, PacketType, RampTime, RxBoost, RxPath},
+    system::ChipMode,
+    PulseShape, RxBw,
+};
+
+const RF: u32 = 868_000_000;
+
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+enum TestKind {Off, Cw, Prbs9, Preamble}
+
+impl TestKind {
+    pub fn next(&self) -> TestKind {
+        match self {
+            TestKind::Off      => TestKind::Cw,
+            TestKind::Cw       => TestKind::Prbs9,
+            TestKind::Prbs9    => TestKind::Preamble,
+            TestKind::Preamble => TestKind::Cw,
+        }
+    }
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    info!("Starting tx_test");
+
+    let board = BoardNucleoL476Rg::init(&spawner).await;
+    let mut lr2021 = board.lr2021;
+
+    // Initialize transceiver
+    lr2021.set_rf(RF).await.expect("SetRF");
+    lr2021.set_rx_path(RxPath::LfPath, RxBoost::Off).await.expect("Setting RX path to LF");
+    lr2021.calib_fe(&[]).await.expect("Front-End calibration");
+
+    match lr2021.get_status().await {
+        Ok((status, intr)) => info!("Calibration Done: {} | {}", status, intr),
+        Err(e) => warn!("Calibration Failed: {}", e),
+    }
+
+    let mut power_dbm = 0;
+    lr2021.set_pa_lf(PaLfMode::LfPaFsm, 6, 7).await.expect("Set PA LF");
+    lr2021.set_tx_params(power_dbm, RampTime::Ramp8u).await.expect("SetTxParams");
+
+    // Modulation used for PRBS and preamble: GFSK 50kb/s, 25kHz deviation
+    lr2021.set_packet_type(PacketType::FskGeneric).await.expect("SetPktType");
+    lr2021.set_fsk_modulation(50_000, PulseShape::Bt0p5, RxBw::Bw111, 25_000).await.expect("SetFskModulation");
+
+    let mut button_press = BoardNucleoL476Rg::get_button_evt();
+    BoardNucleoL476Rg::led_green_set(LedMode::BlinkSlow);
+
+    let mut test = TestKind::Off;
+    loop {
+        match button_press.changed().await {
+            ButtonPressKind::Short => {
+                test = test.next();
+                start_test(&mut lr2021, test).await;
+            }
+            ButtonPressKind::Double => {
+                power_dbm = if power_dbm == 0 {14} else {0};
+                info!("TX power set to {}dBm", power_dbm);
+                lr2021.set_chip_mode(ChipMode::Fs).await.expect("SetFs");
+                lr2021.set_tx_params(power_dbm, RampTime::Ramp8u).await.expect("SetTxParams");
+                start_test(&mut lr2021, test).await;
+            }
+            ButtonPressKind::LongRepeat => {}
+            ButtonPressKind::Long => {
+                test = TestKind::Off;
+                start_test(&mut lr2021, test).await;
+            }
+        }
+    }
+}
+
+async fn start_test(lr2021: &mut Lr2021Stm32, test: TestKind) {
+    // Always stop current test before starting a new one
+    stop_test(lr2021).await.expect("StopTest");
+    match test {
+        TestKind::Off      => {}
+        TestKind::Cw       => tx_continuous_wave(lr2021).await.expect("TxCw"),
+        TestKind::Prbs9    => tx_prbs9(lr2021).await.expect("TxPrbs9"),
+        TestKind::Preamble => tx_infinite_preamble(lr2021).await.expect("TxPreamble"),
+    }
+    let led = if test == TestKind::Off {LedMode::Off} else {LedMode::On};
+    BoardNucleoL476Rg::led_red_set(led);
+    info!("Test mode: {}", test);
+}
@@ -0,0 +1,175 @@
+#![no_std]
+#![no_main]
+
+//! # WiSUN scanning demo application
+//!
+//! The board hops over all channels of the selected region, listening a short time on each one.
+//! Any 802.15.4g frame received is decoded and printed on the debug link.
+//! Green led blinks slowly while scanning, and stays on when the scan is paused
+//!
+//! Short press display RX stats
+//! Double press change WiSUN mode
+//! Long press pause/resume the scan (staying on the current channel)
+
+use defmt::*;
+use embassy_time::{Duration, Timer};
+use {defmt_rtt as _, panic_probe as _};
+
+use embassy_executor::Spawner;
+use embassy_futures::select::{select3, Either3};
+
+use lr2021_apps::{
+    board::{BoardNucleoL476Rg, ButtonPressKind, LedMode, Lr2021Stm32},
+    pkt_utils::{log_rx_stats, take_rx_stats},
+    wisun_utils::{set_wisun_mode, WisunRegion},
+    zigbee_utils::ZigbeeHdr,
+};
+use lr2021::{
+    fsk::GetFskRxStatsRsp,
+    radio::{FallbackMode, PacketType, RxBoost, RxPath},
+    status::{Intr, IRQ_MASK_RX_DONE},
+    system::{ChipMode, DioNum}, wisun::*,
+};
+
+/// Time spent on each channel while scanning
+const DWELL: Duration = Duration::from_millis(250);
+const REGION: WisunRegion = WisunRegion::Na;
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    info!("Starting wisun_rx");
+
+    let board = BoardNucleoL476Rg::init(&spawner).await;
+    let mut lr2021 = board.lr2021;
+    let mut irq = board.irq;
+
+    let mut mode = WisunMode::Mode1a;
+    let mut chan = 0;
+    let mut scan_on = true;
+    let params = WisunPacketParams::new_data(127, WisunFec::Nrnsc, WisunFcsLen::Fcs16b);
+
+    // Initialize transceiver for WiSUN reception
+    let (band_min, _) = REGION.band();
+    lr2021.set_rf(band_min).await.expect("SetRF");
+    lr2021.set_rx_path(RxPath::LfPath, RxBoost::Off).await.expect("Setting RX path to LF");
+    lr2021.calib_fe(&[]).await.expect("Front-End calibration");
+
+    match lr2021.get_status().await {
+        Ok((status, intr)) => info!("Calibration Done: {} | {}", status, intr),
+        Err(e) => warn!("Calibration Failed: {}", e),
+    }
+
+    lr2021.set_fallback(FallbackMode::Fs).await.expect("Set fallback");
+    lr2021.set_packet_type(PacketType::Wisun).await.expect("SetPktType");
+    let rf = set_wisun_mode(&mut lr2021, mode, REGION, chan, params).await.expect("SetWisunMode");
+    info!("Scanning {} channels in {} with {} (first channel @ {}Hz)", REGION.channels(mode), REGION, mode, rf);
+    lr2021.set_rx_continous().await.expect("SetRx");
+
+    // Set DIO7 as IRQ for RX Done
+    lr2021.set_dio_irq(DioNum::Dio7, Intr::new(IRQ_MASK_RX_DONE)).await.expect("Setting DIO7 as IRQ");
+
+    // Wait for a button press for actions
+    let mut button_press = BoardNucleoL476Rg::get_button_evt();
+    BoardNucleoL476Rg::led_green_set(LedMode::BlinkSlow);
+
+    loop {
+        match select3(button_press.changed(), irq.wait_for_rising_edge(), Timer::after(DWELL)).await {
+            Either3::First(press) => {
+                match press {
+                    ButtonPressKind::Short => show_and_clear_rx_stats(&mut lr2021).await,
+                    ButtonPressKind::Double => {
+                        mode = next_mode(mode);
+                        chan = 0;
+                        retune(&mut lr2021, mode, chan, params).await;
+                        info!("Switching to {} ({} channels)", mode, REGION.channels(mode));
+                    }
+                    ButtonPressKind::LongRepeat => {}
+                    ButtonPressKind::Long => {
+                        scan_on = !scan_on;
+                        let led = if scan_on {LedMode::BlinkSlow} else {LedMode::On};
+                        BoardNucleoL476Rg::led_green_set(led);
+                        info!("Scan {} on channel {}", if scan_on {"resumed"} else {"paused"}, chan);
+                    }
+                }
+            }
+            // RX Interrupt
+            Either3::Second(_) => {
+                let intr = lr2021.get_and_clear_irq().await.expect("GetIrqs");
+                if intr.rx_done() && !intr.crc_error() {
+                    BoardNucleoL476Rg::led_green_set(LedMode::Flash);
+                    show_rx_pkt(&mut lr2021, chan).await;
+                } else {
+                    BoardNucleoL476Rg::led_red_set(LedMode::Flash);
+                    lr2021.clear_rx_fifo().await.unwrap();
+                }
+                lr2021.set_rx_continous().await.expect("SetRx");
+            }
+            // Dwell time elapsed: move to next channel
+            Either3::Third(_) => {
+                if scan_on {
+                    chan = (chan + 1) % REGION.channels(mode);
+                    retune(&mut lr2021, mode, chan, params).await;
+                }
+            }
+        }
+    }
+}
+
+/// Next mode allowed in the region
+fn next_mode(mode: WisunMode) -> WisunMode {
+    let mut mode = mode;
+    loop {
+        mode = match mode {
+            WisunMode::Mode1a => WisunMode::Mode1b,
+            WisunMode::Mode1b => WisunMode::Mode2a,
+            WisunMode::Mode2a => WisunMode::Mode2b,
+            WisunMode::Mode2b => WisunMode::Mode3,
+            WisunMode::Mode3  => WisunMode::Mode4a,
+            WisunMode::Mode4a => WisunMode::Mode4b,
+            WisunMode::Mode4b => WisunMode::Mode5,
+            WisunMode::Mode5  => WisunMode::Mode1a,
+        };
+        if REGION.plan(mode).is_some() {
+            return mode;
+        }
+    }
+}
+
+async fn retune(lr2021: &mut Lr2021Stm32, mode: WisunMode, chan: u16, params: WisunPacketParams) {
+    lr2021.set_chip_mode(ChipMode::Fs).await.expect("SetFs");
+    set_wisun_mode(lr2021, mode, REGION, chan, params).await.expect("SetWisunMode");
+    lr2021.set_rx_continous().await.expect("SetRx");
+}
+
+async fn show_and_clear_rx_stats(lr2021: &mut Lr2021Stm32) {
+    let stats = take_rx_stats::<GetFskRxStatsRsp>(lr2021).await.expect("RX stats");
+    log_rx_stats(&stats);
+}
+
+async fn show_rx_pkt(lr2021: &mut Lr2021Stm32, chan: u16) {
+    let pkt_len = lr2021.get_rx_pkt_len().await.expect("RX Fifo level") as usize;
+    let status = lr2021.get_fsk_packet_status().await.expect("RX status");
+    lr2021.rd_rx_fifo(pkt_len).await.expect("RX FIFO Read");
+
+    let lqi = status.lqi();
+    let lqi_frac = (lqi&3) * 25;
+
+    let mut bytes = lr2021.buffer().iter().take(pkt_len).copied();
+    if let Some(hdr) = ZigbeeHdr::parse(&mut bytes) {
+        let hdr_size = pkt_len - bytes.len();
+        info!("[Ch{}] {} {:02x} | RSSI=-{}dBm, LQI={}.{:02}",
+            chan,
+            hdr,
+            lr2021.buffer()[hdr_size..pkt_len],
+            status.rssi_avg()>>1,
+            lqi>>2, lqi_frac
+        );
+    } else {
+        info!("[Ch{}] [Raw] {:02x} | RSSI=-{}dBm, LQI={}.{:02}",
+            chan,
+            lr2021.buffer()[..pkt_len],
+            status.rssi_avg()>>1,
+            lqi>>2, lqi_frac
+        );
+    }
+}
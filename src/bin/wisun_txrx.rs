@@ -24,8 +24,9 @@ use embassy_executor::Spawner;
 use embassy_futures::select::{select3, Either3};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
 
-use lr2021_apps::board::{BoardNucleoL476Rg, BoardRole, ButtonPressKind, LedMode, Lr2021Stm32};
+use lr2021_apps::{board::{BoardNucleoL476Rg, BoardRole, ButtonPressKind, LedMode, Lr2021Stm32}, pkt_utils::{log_rx_stats, take_rx_stats}, uart_command::{dispatch, CmdLine}};
 use lr2021::{
+    fsk::GetFskRxStatsRsp,
     radio::{PacketType, RampTime, RxBoost, RxPath}, status::{Intr, IRQ_MASK_RX_DONE, IRQ_MASK_TX_DONE}, system::{ChipMode, DioNum}, wisun::*, Lr2021Error, RxBw
 };
 
@@ -37,6 +38,13 @@ enum UartCmd {
 }
 type SignalCmd = Signal<CriticalSectionRawMutex, UartCmd>;
 static CMD : SignalCmd = Signal::new();
+/// UART commands: one letter per command
+const UART_CMDS: [(u8, UartCmd); 4] = [
+    (b's', UartCmd::SwitchTxRx),
+    (b't', UartCmd::StartTx),
+    (b'a', UartCmd::ToggleAuto),
+    (b'h', UartCmd::ChangeMode),
+];
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
@@ -161,14 +169,8 @@ async fn main(spawner: Spawner) {
 }
 
 async fn show_and_clear_rx_stats(lr2021: &mut Lr2021Stm32) {
-    let stats = lr2021.get_fsk_rx_stats().await.expect("RX stats");
-    info!("[RX] Clearing stats | RX={}, CRC Err={}, LenErr={} | Detect={}, SyncFail={}",
-        stats.pkt_rx(),
-        stats.crc_error(),
-        stats.len_error(),
-        stats.pbl_det(),
-        stats.sync_fail(),
-    );
+    let stats = take_rx_stats::<GetFskRxStatsRsp>(lr2021).await.expect("RX stats");
+    log_rx_stats(&stats);
 }
 
 async fn send_pkt(lr2021: &mut Lr2021Stm32, pkt_id: &mut u8) {
@@ -237,14 +239,9 @@ pub async fn handle_uart(mut uart: Uart<'static, Async>, sig_cmd: &'static Signa
         // Wait for a command
         let mut buffer = [0u8;8];
         uart.read_until_idle(&mut buffer).await.ok();
-        // Parsing: either R[min]-[max] or S[step]
-        let cmd = match buffer[0] {
-            b'S' | b's' => UartCmd::SwitchTxRx,
-            b'T' | b't' => UartCmd::StartTx,
-            b'A' | b'a' => UartCmd::ToggleAuto,
-            b'H' | b'h' => UartCmd::ChangeMode,
-            _ => UartCmd::Invalid,
-        };
+        let cmd = CmdLine::parse(&buffer)
+            .and_then(|line| dispatch(&UART_CMDS, &line))
+            .unwrap_or(UartCmd::Invalid);
         // info!("[UART] Command = {}", cmd);
         uart.write(&buffer[0..1]).await.ok();
         sig_cmd.signal(cmd);
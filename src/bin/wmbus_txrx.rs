@@ -8,6 +8,7 @@
 //! Long press on user button switch the board role between TX and RX
 //! Short press either send a packet of incrementing byte or display RX stats in RX
 //! Double press change WiSUN mode
+//! Valid wM-Bus frames received (all block CRC OK) are decoded, other packets are displayed raw
 //!
 //! The board also accept command by UART (running at 444_444bauds), one character per command:
 //!  - 's' to switch mode
@@ -22,12 +23,19 @@ use embassy_executor::Spawner;
 use embassy_futures::select::{select3, Either3};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
 
-use lr2021_apps::board::{BoardNucleoL476Rg, BoardRole, ButtonPressKind, LedMode, Lr2021Stm32};
+use lr2021_apps::{
+    board::{BoardNucleoL476Rg, BoardRole, ButtonPressKind, LedMode, Lr2021Stm32},
+    pkt_utils::{log_rx_stats, receive_packet, take_rx_stats},
+    uart_command::{dispatch, CmdLine},
+    wmbus_utils::{wmbus_remove_crc, WmbusHdr, WmbusModeExt},
+};
 use lr2021::{
+    fsk::GetFskRxStatsRsp,
     radio::{PacketType, RampTime, RxBoost, RxPath}, status::{Intr, IRQ_MASK_RX_DONE, IRQ_MASK_TX_DONE}, system::{ChipMode, DioNum}, wmbus::*, Lr2021Error
 };
 
 const PLD_SIZE : u8 = 10;
+const FORMAT : WmbusFormat = WmbusFormat::FormatA;
 
 #[derive(Debug, Clone, Copy, Format)]
 enum UartCmd {
@@ -35,6 +43,12 @@ enum UartCmd {
 }
 type SignalCmd = Signal<CriticalSectionRawMutex, UartCmd>;
 static CMD : SignalCmd = Signal::new();
+/// UART commands: one letter per command
+const UART_CMDS: [(u8, UartCmd); 3] = [
+    (b's', UartCmd::SwitchTxRx),
+    (b't', UartCmd::StartTx),
+    (b'h', UartCmd::ChangeMode),
+];
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
@@ -51,7 +65,7 @@ async fn main(spawner: Spawner) {
     let mut mode = WmbusMode::ModeS;
 
     // Initialize transceiver for WMBus communication
-    let rf = mode.rf(0, WmbusSubBand::A);
+    let rf = mode.checked_rf(0, WmbusSubBand::A).expect("WMBus channel");
     lr2021.set_rf(rf).await.expect("SetRF");
     lr2021.set_rx_path(RxPath::LfPath, RxBoost::Off).await.expect("Setting RX path to LF");
     lr2021.calib_fe(&[]).await.expect("Front-End calibration");
@@ -61,7 +75,7 @@ async fn main(spawner: Spawner) {
         Err(e) => warn!("Calibration Failed: {}", e),
     }
 
-    let params = WmbusPacketParams::new(mode, WmbusFormat::FormatA, PLD_SIZE);
+    let params = WmbusPacketParams::new(mode, FORMAT, PLD_SIZE);
     lr2021.set_packet_type(PacketType::Wmbus).await.expect("SetPktType");
     lr2021.set_wmbus_packet(params).await.expect("SetPktParams");
 
@@ -110,7 +124,7 @@ async fn main(spawner: Spawner) {
 
                 } else if !intr.crc_error() {
                     BoardNucleoL476Rg::led_green_set(LedMode::Flash);
-                    show_rx_pkt(&mut lr2021).await;
+                    show_rx_pkt(&mut lr2021, intr).await;
                 } else {
                     warn!("CRC Error");
                     lr2021.clear_rx_fifo().await.unwrap();
@@ -135,14 +149,8 @@ async fn main(spawner: Spawner) {
 }
 
 async fn show_and_clear_rx_stats(lr2021: &mut Lr2021Stm32) {
-    let stats = lr2021.get_fsk_rx_stats().await.expect("RX stats");
-    info!("[RX] Clearing stats | RX={}, CRC Err={}, LenErr={} | Detect={}, SyncFail={}",
-        stats.pkt_rx(),
-        stats.crc_error(),
-        stats.len_error(),
-        stats.pbl_det(),
-        stats.sync_fail(),
-    );
+    let stats = take_rx_stats::<GetFskRxStatsRsp>(lr2021).await.expect("RX stats");
+    log_rx_stats(&stats);
 }
 
 async fn send_pkt(lr2021: &mut Lr2021Stm32, pkt_id: &mut u8) {
@@ -182,9 +190,9 @@ async fn switch_mode(lr2021: &mut Lr2021Stm32, mode: &mut WmbusMode, is_rx: bool
         WmbusMode::ModeN19p2 => WmbusMode::ModeF2,
         _                    => WmbusMode::ModeS,
     };
-    let rf = mode.rf(0, WmbusSubBand::A);
+    let rf = mode.checked_rf(0, WmbusSubBand::A).expect("WMBus channel");
     lr2021.set_rf(rf).await.expect("SetRF");
-    let params = WmbusPacketParams::new(*mode, WmbusFormat::FormatA, PLD_SIZE);
+    let params = WmbusPacketParams::new(*mode, FORMAT, PLD_SIZE);
     lr2021.set_wmbus_packet(params).await.expect("SetPktParams");
     info!("Switching to {} @ {}Hz", mode, rf);
 
@@ -194,17 +202,29 @@ async fn switch_mode(lr2021: &mut Lr2021Stm32, mode: &mut WmbusMode, is_rx: bool
     Ok(())
 }
 
-async fn show_rx_pkt(lr2021: &mut Lr2021Stm32) {
-    let pkt_len = lr2021.get_rx_pkt_len().await.expect("RX Fifo level") as usize;
-    let status = lr2021.get_fsk_packet_status().await.expect("RX status");
-    lr2021.rd_rx_fifo(pkt_len).await.expect("RX FIFO Read");
-    let lqi = status.lqi();
+async fn show_rx_pkt(lr2021: &mut Lr2021Stm32, intr: Intr) {
+    let mut data = [0u8; 256];
+    let (pkt_len, info) = receive_packet(lr2021, intr, &mut data).await.expect("RX Packet");
+    let lqi = info.lqi.unwrap_or(0);
     let lqi_frac = (lqi&3) * 25;
-    info!("[RX] Payload = {:02x} | RSSI=-{}dBm, LQI={}.{:02}",
-        lr2021.buffer()[..pkt_len],
-        status.rssi_avg()>>1,
-        lqi>>2, lqi_frac
-    );
+    // Try to decode a valid wM-Bus frame, otherwise display raw data
+    let mut frame = [0u8; 256];
+    let hdr = wmbus_remove_crc(FORMAT, &data[..pkt_len], &mut frame)
+        .and_then(|len| WmbusHdr::parse(&frame[..len]).map(|hdr| (hdr, len)));
+    if let Some((hdr, len)) = hdr {
+        info!("[RX] {} {:02x} | RSSI={}dBm, LQI={}.{:02}",
+            hdr,
+            frame[WmbusHdr::LEN..len],
+            info.rssi_avg_dbm,
+            lqi>>2, lqi_frac
+        );
+    } else {
+        info!("[RX] Payload = {:02x} | RSSI={}dBm, LQI={}.{:02}",
+            data[..pkt_len],
+            info.rssi_avg_dbm,
+            lqi>>2, lqi_frac
+        );
+    }
 }
 
 #[embassy_executor::task]
@@ -213,13 +233,9 @@ pub async fn handle_uart(mut uart: Uart<'static, Async>, sig_cmd: &'static Signa
         // Wait for a command
         let mut buffer = [0u8;8];
         uart.read_until_idle(&mut buffer).await.ok();
-        // Parsing: either R[min]-[max] or S[step]
-        let cmd = match buffer[0] {
-            b'S' | b's' => UartCmd::SwitchTxRx,
-            b'T' | b't' => UartCmd::StartTx,
-            b'H' | b'h' => UartCmd::ChangeMode,
-            _ => UartCmd::Invalid,
-        };
+        let cmd = CmdLine::parse(&buffer)
+            .and_then(|line| dispatch(&UART_CMDS, &line))
+            .unwrap_or(UartCmd::Invalid);
         // info!("[UART] Command = {}", cmd);
         uart.write(&buffer[0..1]).await.ok();
         sig_cmd.signal(cmd);
@@ -10,10 +10,10 @@ use {defmt_rtt as _, panic_probe as _};
 use embassy_executor::Spawner;
 use embassy_futures::select::{select, Either};
 
-use lr2021_apps::{board::{BoardNucleoL476Rg, ButtonPressKind, LedMode, Lr2021Stm32}, zigbee_utils::ZigbeeFrameType};
+use lr2021_apps::{board::{BoardNucleoL476Rg, ButtonPressKind, LedMode, Lr2021Stm32}, pkt_utils::{log_rx_stats, take_rx_stats}, radio_utils::set_rf_auto_path, zigbee_utils::ZigbeeFrameType};
 use lr2021_apps::zigbee_utils::{ZigbeeHdr, ZigbeeCmd};
 
-use lr2021::{radio::{FallbackMode, PacketType, RampTime, RxBoost, RxPath}, system::{ChipMode, DioNum}};
+use lr2021::{radio::{FallbackMode, PacketType, RampTime, RxBoost}, system::{ChipMode, DioNum}};
 use lr2021::status::{Intr, IRQ_MASK_RX_DONE, IRQ_MASK_TX_DONE};
 // use lr2021::system::ChipMode;
 use lr2021::zigbee::*;
@@ -51,10 +51,7 @@ async fn main(spawner: Spawner) {
     let mut chan = AdvChanRf::Chan15;
 
     // Initialize transceiver for LoRa communication
-    lr2021.set_rf(chan.freq()).await.expect("Setting RF to 2.425 GHz");
-    lr2021.set_rx_path(RxPath::HfPath, RxBoost::Off).await.expect("Setting RX path to LF");
-    // lr2021.set_rf(2_400_000_000).await.expect("Setting RF to 2.4GHz");
-    // lr2021.set_rx_path(RxPath::HfPath, 0).await.expect("Setting RX path to HF");
+    set_rf_auto_path(&mut lr2021, chan.freq(), RxBoost::Off).await.expect("Setting RF to 2.425 GHz");
     lr2021.calib_fe(&[]).await.expect("Front-End calibration");
     match lr2021.get_status().await {
         Ok((status, intr)) => info!("Calibration Done: {} | {}", status, intr),
@@ -88,6 +85,7 @@ async fn main(spawner: Spawner) {
                     ButtonPressKind::Double => {
                         info!("ButtonPressKind::Double");
                     }
+                    ButtonPressKind::LongRepeat => {}
                     // Long press:
                     //  - When spy, switch channel
                     ButtonPressKind::Long => {
@@ -135,13 +133,8 @@ async fn switch_channel(lr2021: &mut Lr2021Stm32, chan: AdvChanRf) {
 }
 
 async fn show_and_clear_rx_stats(lr2021: &mut Lr2021Stm32) {
-    let stats = lr2021.get_zigbee_rx_stats().await.expect("RX stats");
-    info!("[RX] Clearing stats | RX={}, CRC Err={}, LenErr={}",
-        stats.pkt_rx(),
-        stats.crc_error(),
-        stats.len_error()
-    );
-    lr2021.clear_rx_stats().await.unwrap();
+    let stats = take_rx_stats::<ZigbeeRxStatsRsp>(lr2021).await.expect("RX stats");
+    log_rx_stats(&stats);
 }
 
 async fn handle_rx_pkt(lr2021: &mut Lr2021Stm32) {
@@ -10,7 +10,7 @@
 //! In SPY mode, the message received are decoded and print on the debug link
 
 use defmt::*;
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant};
 use {defmt_rtt as _, panic_probe as _};
 
 use embassy_executor::Spawner;
@@ -18,9 +18,9 @@ use embassy_futures::select::{select, Either};
 
 use embassy_stm32::gpio::Output;
 
-use lr2021_apps::{board::{BoardNucleoL476Rg, ButtonPressKind, LedMode, Lr2021Stm32}, zwave_utils::{BinaryCmd, NamingCmd}};
+use lr2021_apps::{board::{BoardNucleoL476Rg, ButtonPressKind, LedMode, Lr2021Stm32}, pkt_utils::{log_rx_stats, take_rx_stats}, radio_utils::{schedule_tx_at, set_rf_auto_path, timestamp_since}, zwave_utils::{BinaryCmd, NamingCmd}};
 use lr2021_apps::zwave_utils::{ProtCmd, ZwaveHdrType, ZwavePhyHdr, ManufacturerCmd, VersionCmd, ZwaveCmd};
-use lr2021::radio::{FallbackMode, PaLfMode, PacketType, RampTime, RxBoost, RxPath, TimestampIndex, TimestampSource};
+use lr2021::radio::{FallbackMode, PaLfMode, PacketType, RampTime, RxBoost, TimestampIndex, TimestampSource};
 use lr2021::status::{Intr, IRQ_MASK_RX_DONE, IRQ_MASK_TX_DONE};
 use lr2021::system::{ChipMode, DioNum};
 use lr2021::zwave::*;
@@ -82,10 +82,8 @@ async fn main(spawner: Spawner) {
     let mut irq = board.irq;
 
     // Initialize transceiver for LoRa communication
-    lr2021.set_rf(868_400_000).await.expect("Setting RF to 868.4MHz");
-    lr2021.set_rx_path(RxPath::LfPath, RxBoost::Off).await.expect("Setting RX path to LF");
-    // lr2021.set_rf(2_400_000_000).await.expect("Setting RF to 2.4GHz");
-    // lr2021.set_rx_path(RxPath::HfPath, 0).await.expect("Setting RX path to HF");
+    set_rf_auto_path(&mut lr2021, 868_400_000, RxBoost::Off).await.expect("Setting RF to 868.4MHz");
+    // set_rf_auto_path(&mut lr2021, 2_400_000_000, RxBoost::Off).await.expect("Setting RF to 2.4GHz");
     lr2021.calib_fe(&[]).await.expect("Front-End calibration");
     match lr2021.get_status().await {
         Ok((status, intr)) => info!("Calibration Done: {} | {}", status, intr),
@@ -143,6 +141,7 @@ async fn main(spawner: Spawner) {
                             info!("Board in SPY mode");
                         }
                     }
+                    ButtonPressKind::LongRepeat => {}
                     // Long press:
                     //  - When active send a NOP to the controller
                     //  - When spy, maybe enable filtering and switch among all networked seen ?
@@ -231,13 +230,8 @@ async fn main(spawner: Spawner) {
 }
 
 async fn show_and_clear_rx_stats(lr2021: &mut Lr2021Stm32) {
-    let stats = lr2021.get_zwave_rx_stats().await.expect("RX stats");
-    info!("[RX] Clearing stats | RX={}, CRC Err={}, LenErr={}",
-        stats.pkt_rx(),
-        stats.crc_error(),
-        stats.len_error()
-    );
-    lr2021.clear_rx_stats().await.unwrap();
+    let stats = take_rx_stats::<ZwaveRxStatsRsp>(lr2021).await.expect("RX stats");
+    log_rx_stats(&stats);
 }
 
 async fn send_message(lr2021: &mut Lr2021Stm32, state: &mut BoardState, msg: &[u8]) {
@@ -252,15 +246,10 @@ async fn send_message(lr2021: &mut Lr2021Stm32, state: &mut BoardState, msg: &[u
     lr2021.wr_tx_fifo(len).await.expect("FIFO write");
     // For Ack packet we need to respect some precise timing: check timestamp and use a TX trigger
     if state.phy_hdr.hdr_type == ZwaveHdrType::Ack {
-        let rx_ts_tick = lr2021.get_timestamp(TimestampIndex::Ts0).await.expect("GetTs");
-        let rx_ts_ns = (rx_ts_tick as u64 * 125) >> 2; // 32MHz -> 31.25ns
+        let since_rx = timestamp_since(lr2021, TimestampIndex::Ts0).await.expect("GetTs");
         // Ensure the packet will starts after ~ 1ms
-        let sleep = Duration::from_micros(1000) - Duration::from_nanos(rx_ts_ns);
-        Timer::after(sleep).await;
-        state.trigger_tx.set_high();
-        lr2021.wait_ready(Duration::from_micros(100)).await.expect("WaitTxTrigger");
-        Timer::after_micros(1).await;
-        state.trigger_tx.set_low();
+        let target = Instant::now() + Duration::from_micros(1000) - since_rx;
+        schedule_tx_at(lr2021, &mut state.trigger_tx, target).await.expect("WaitTxTrigger");
     } else {
         lr2021.set_tx(0).await.expect("SetTx");
     }
@@ -1,4 +1,8 @@
 use defmt::{debug, info, warn, Format, write};
+use embassy_time::{Duration, Instant, Timer};
+use lr2021::{ble::ChannelType, system::ChipMode};
+
+use crate::{board::Lr2021Stm32, error::AppError};
 
 #[derive(Debug, Clone, Copy, Format, PartialEq)]
 pub enum BleAdvType {
@@ -324,10 +328,10 @@ pub fn print_ble_adv(addr_seen: &mut AddrList, bytes: &[u8], hdr: BleAdvHeader,
             info!("[{}] From {:06x} to {:06x} | RSSI -{}dBm", hdr_type, addr, addr_scan, rssi_dbm);
         }
         BleAdvType::ConnectInd => {
-            // Length already checked, bytes is known to be 14 bytes at this point
-            let addr_conn = ((bytes[8] as u64) << 40) | ((bytes[9] as u64) << 32) | ((bytes[10] as u64) << 24)
-                    | ((bytes[11] as u64) << 16) | ((bytes[12] as u64) << 8) |  bytes[13] as u64 ;
-            info!("[{}] From {:06x} to {:06x} | LL Data = {=[u8]:02x} | RSSI -{}dBm", hdr_type, addr, addr_conn, bytes[14..], rssi_dbm);
+            match BleConnectInd::parse(bytes) {
+                Some(req) => info!("[{}] {} | RSSI -{}dBm", hdr_type, req, rssi_dbm),
+                None => warn!("[{}] Invalid length {} | RSSI -{}dBm", hdr_type, bytes.len(), rssi_dbm),
+            }
         }
         // Parse Advertising Data blocks
         _ => {
@@ -462,3 +466,364 @@ impl Format for AddrList {
         }
     }
 }
+
+/// BLE channel index (0 to 36 for data channels, 37 to 39 for advertising channels)
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub struct BleChannel(u8);
+
+impl BleChannel {
+    pub const ADV37: BleChannel = BleChannel(37);
+    pub const ADV38: BleChannel = BleChannel(38);
+    pub const ADV39: BleChannel = BleChannel(39);
+
+    /// Create a channel from its index, None if index is above 39
+    pub fn new(idx: u8) -> Option<Self> {
+        if idx < 40 {Some(BleChannel(idx))} else {None}
+    }
+
+    /// Channel index
+    pub fn index(&self) -> u8 {
+        self.0
+    }
+
+    /// True for the three advertising channels
+    pub fn is_advertising(&self) -> bool {
+        self.0 >= 37
+    }
+
+    /// RF channel index (frequency = 2402MHz + 2MHz * index)
+    /// Advertising channels are placed at both edges and in the middle of the band:
+    ///  - Channel 37 -> RF 0 (2402MHz)
+    ///  - Channel 0-10 -> RF 1-11
+    ///  - Channel 38 -> RF 12 (2426MHz)
+    ///  - Channel 11-36 -> RF 13-38
+    ///  - Channel 39 -> RF 39 (2480MHz)
+    pub fn rf_index(&self) -> u8 {
+        match self.0 {
+            37 => 0,
+            38 => 12,
+            39 => 39,
+            c if c < 11 => c + 1,
+            c => c + 2,
+        }
+    }
+
+    /// Center frequency in Hz
+    pub fn frequency_hz(&self) -> u32 {
+        2_402_000_000 + 2_000_000 * self.rf_index() as u32
+    }
+
+    /// Initial value of the whitening LFSR
+    /// The 7-bit LFSR is initialized with the channel index (MSB in position 1) and 1 in position 0
+    pub fn whitening_init(&self) -> u8 {
+        ((self.0 & 0x3F).reverse_bits() >> 1) | 1
+    }
+}
+
+/// CRC initial value used on advertising channels
+pub const BLE_CRC_INIT_ADV: u32 = 0x555555;
+
+/// Compute the 24-bit BLE CRC (polynomial x^24+x^10+x^9+x^6+x^4+x^3+x+1) over the PDU (header + payload)
+/// Data are processed LSB first as transmitted on air.
+/// The returned value is in transmission order: LSB is the first byte sent after the PDU.
+///
+/// Reference value, computed with the bit-serial LFSR described in the Core spec (Vol 6, Part B, 3.1.1):
+/// ADV_IND from AdvA 11:22:33:44:55:66 without advertising data, on an advertising channel (init 0x555555)
+///  - PDU on air: `00 06 66 55 44 33 22 11`
+///  - ble_crc24(BLE_CRC_INIT_ADV, pdu) = 0x622C5A, i.e. CRC bytes `5A 2C 62` on air
+pub fn ble_crc24(init: u32, data: &[u8]) -> u32 {
+    // LFSR is implemented reflected: reverse the initial value and use the reflected polynomial
+    let mut state = (init & 0xFF_FFFF).reverse_bits() >> 8;
+    for &b in data {
+        let mut cur = b;
+        for _ in 0..8 {
+            let next_bit = (state ^ cur as u32) & 1;
+            cur >>= 1;
+            state >>= 1;
+            if next_bit != 0 {
+                state |= 1 << 23;
+                state ^= 0x5A6000;
+            }
+        }
+    }
+    state
+}
+
+/// Apply BLE (de)whitening on a buffer (PDU and CRC) received or to be sent on a channel
+/// Whitening uses the 7-bit LFSR x^7+x^4+1 initialized from the channel index,
+/// applying it a second time restores the original data.
+pub fn ble_dewhiten(channel: u8, data: &mut [u8]) {
+    let mut lfsr = BleChannel(channel & 0x3F).whitening_init();
+    for b in data.iter_mut() {
+        let mut out = 0u8;
+        for i in 0..8 {
+            let bit = (lfsr >> 6) & 1;
+            out |= (((*b >> i) & 1) ^ bit) << i;
+            lfsr = (lfsr << 1) & 0x7F;
+            if bit != 0 {
+                lfsr ^= 0x11;
+            }
+        }
+        *b = out;
+    }
+}
+
+/// Maximum advertising data length in legacy advertising PDUs
+pub const BLE_ADV_DATA_MAX: usize = 31;
+
+/// Read a 6-byte device address (MSB first)
+fn read_ble_addr(src: &[u8]) -> u64 {
+    src.iter().take(6).fold(0, |addr, &b| (addr << 8) | b as u64)
+}
+
+/// Write a 6-byte device address (MSB first)
+fn write_ble_addr(dst: &mut [u8], addr: u64) {
+    for (i, b) in dst.iter_mut().take(6).enumerate() {
+        *b = ((addr >> (40 - 8 * i)) & 0xFF) as u8;
+    }
+}
+
+/// Send a scan request to target, using public addresses (TxAdd/RxAdd cleared)
+pub async fn ble_send_scan_req(lr2021: &mut Lr2021Stm32, target: u64, own: u64) -> Result<(), AppError> {
+    let len = 14;
+    let buffer = lr2021.buffer_mut();
+    buffer[0] = BleAdvType::ScanReq as u8;
+    buffer[1] = 12;
+    write_ble_addr(&mut buffer[2..8], own);
+    write_ble_addr(&mut buffer[8..14], target);
+    lr2021.wr_tx_fifo(len).await?;
+    lr2021.set_ble_tx(len as u8).await?;
+    Ok(())
+}
+
+/// Send a scan response with advertising data, using a public address (TxAdd cleared)
+/// Advertising data is limited to 31 bytes for legacy scan responses
+pub async fn ble_send_scan_rsp(lr2021: &mut Lr2021Stm32, own: u64, adv_data: &[u8]) -> Result<(), AppError> {
+    if adv_data.len() > BLE_ADV_DATA_MAX {
+        return Err(AppError::PayloadTooLong(adv_data.len()));
+    }
+    let len = 8 + adv_data.len();
+    let buffer = lr2021.buffer_mut();
+    buffer[0] = BleAdvType::ScanRsp as u8;
+    buffer[1] = (6 + adv_data.len()) as u8;
+    write_ble_addr(&mut buffer[2..8], own);
+    buffer[8..len].copy_from_slice(adv_data);
+    lr2021.wr_tx_fifo(len).await?;
+    lr2021.set_ble_tx(len as u8).await?;
+    Ok(())
+}
+
+/// Length of the link layer data in a connection request
+pub const BLE_LL_DATA_LEN: usize = 22;
+/// Unit of the connection timing parameters (1.25ms)
+const BLE_CONN_UNIT: Duration = Duration::from_micros(1250);
+/// Number of data channels
+const BLE_DATA_CHANNELS: u8 = 37;
+
+/// Link layer data of a connection request (CONNECT_IND), defining the connection parameters
+/// Multi-byte fields are sent LSB first.
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub struct BleLlData {
+    /// Access address of the data packets
+    pub access_addr: u32,
+    /// Initial value of the CRC (24 bits)
+    pub crc_init: u32,
+    /// Transmit window size (unit 1.25ms)
+    pub win_size: u8,
+    /// Transmit window offset (unit 1.25ms)
+    pub win_offset: u16,
+    /// Connection interval (unit 1.25ms)
+    pub interval: u16,
+    /// Peripheral latency (number of connection events)
+    pub latency: u16,
+    /// Supervision timeout (unit 10ms)
+    pub timeout: u16,
+    /// Channel map: bit n set when data channel n is used (37 bits)
+    pub chan_map: u64,
+    /// Hop increment (5 to 16)
+    pub hop: u8,
+    /// Sleep clock accuracy (0 to 7)
+    pub sca: u8,
+}
+
+impl BleLlData {
+    /// Connection parameters using all data channels, with a 30ms interval,
+    /// a 2.5ms transmit window starting right after the connection request and a 1s supervision timeout
+    pub fn new(access_addr: u32, crc_init: u32) -> Self {
+        Self {
+            access_addr,
+            crc_init: crc_init & 0xFF_FFFF,
+            win_size: 2,
+            win_offset: 0,
+            interval: 24,
+            latency: 0,
+            timeout: 100,
+            chan_map: (1 << BLE_DATA_CHANNELS) - 1,
+            hop: 7,
+            sca: 0,
+        }
+    }
+
+    /// Encode the link layer data as sent in the connection request
+    pub fn to_bytes(&self) -> [u8; BLE_LL_DATA_LEN] {
+        let mut b = [0; BLE_LL_DATA_LEN];
+        b[0..4].copy_from_slice(&self.access_addr.to_le_bytes());
+        b[4..7].copy_from_slice(&self.crc_init.to_le_bytes()[..3]);
+        b[7] = self.win_size;
+        b[8..10].copy_from_slice(&self.win_offset.to_le_bytes());
+        b[10..12].copy_from_slice(&self.interval.to_le_bytes());
+        b[12..14].copy_from_slice(&self.latency.to_le_bytes());
+        b[14..16].copy_from_slice(&self.timeout.to_le_bytes());
+        b[16..21].copy_from_slice(&self.chan_map.to_le_bytes()[..5]);
+        b[21] = (self.hop & 0x1F) | (self.sca << 5);
+        b
+    }
+
+    /// Decode the link layer data of a connection request, None if too short
+    pub fn from_bytes(b: &[u8]) -> Option<Self> {
+        let b = b.get(..BLE_LL_DATA_LEN)?;
+        let mut chan_map = [0u8; 8];
+        chan_map[..5].copy_from_slice(&b[16..21]);
+        Some(Self {
+            access_addr: u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            crc_init: u32::from_le_bytes([b[4], b[5], b[6], 0]),
+            win_size: b[7],
+            win_offset: u16::from_le_bytes([b[8], b[9]]),
+            interval: u16::from_le_bytes([b[10], b[11]]),
+            latency: u16::from_le_bytes([b[12], b[13]]),
+            timeout: u16::from_le_bytes([b[14], b[15]]),
+            chan_map: u64::from_le_bytes(chan_map),
+            hop: b[21] & 0x1F,
+            sca: b[21] >> 5,
+        })
+    }
+
+    /// Transmit window of the first connection event, relative to the end of the connection request:
+    /// the central sends its first packet between 1.25ms + win_offset and 1.25ms + win_offset + win_size.
+    /// Return the start and the size of the window
+    pub fn transmit_window(&self) -> (Duration, Duration) {
+        let start = BLE_CONN_UNIT * (1 + self.win_offset as u32);
+        (start, BLE_CONN_UNIT * self.win_size as u32)
+    }
+
+    /// Time between two connection events
+    pub fn interval_duration(&self) -> Duration {
+        BLE_CONN_UNIT * self.interval as u32
+    }
+
+    /// Number of data channels used
+    pub fn used_channels(&self) -> u8 {
+        (self.chan_map & ((1 << BLE_DATA_CHANNELS) - 1)).count_ones() as u8
+    }
+}
+
+/// Payload length of a legacy connection request
+pub const BLE_CONNECT_IND_LEN: usize = 12 + BLE_LL_DATA_LEN;
+
+/// Connection request (CONNECT_IND)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BleConnectInd {
+    /// Address of the initiator (central)
+    pub init_addr: u64,
+    /// Address of the advertiser (peripheral)
+    pub adv_addr: u64,
+    /// Connection parameters
+    pub ll: BleLlData,
+}
+
+impl BleConnectInd {
+    /// Parse a connection request PDU (header included), None if the type or length is invalid
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let hdr = BleAdvHeader(*bytes.first()?);
+        if hdr.get_type() != BleAdvType::ConnectInd
+            || *bytes.get(1)? as usize != BLE_CONNECT_IND_LEN
+            || bytes.len() < BLE_CONNECT_IND_LEN + 2 {
+            return None;
+        }
+        Some(Self {
+            init_addr: read_ble_addr(&bytes[2..8]),
+            adv_addr: read_ble_addr(&bytes[8..14]),
+            ll: BleLlData::from_bytes(&bytes[14..])?,
+        })
+    }
+}
+
+impl Format for BleConnectInd {
+    fn format(&self, fmt: defmt::Formatter) {
+        let ll = &self.ll;
+        write!(fmt, "From {:06x} to {:06x} | AA={:08x}, CRCInit={:06x}, Win={}+{}, Interval={}, Latency={}, Timeout={}, ChM={:010x}, Hop={}, SCA={}",
+            self.init_addr, self.adv_addr,
+            ll.access_addr, ll.crc_init,
+            ll.win_offset, ll.win_size,
+            ll.interval, ll.latency, ll.timeout,
+            ll.chan_map, ll.hop, ll.sca
+        );
+    }
+}
+
+/// State of a connection: event counter and channel selection (algorithm #1)
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub struct BleConnection {
+    pub ll: BleLlData,
+    /// Number of connection events started
+    pub event_counter: u16,
+    last_unmapped: u8,
+}
+
+impl BleConnection {
+    pub fn new(ll: BleLlData) -> Self {
+        Self {ll, event_counter: 0, last_unmapped: 0}
+    }
+
+    /// Select the channel of the next connection event (channel selection algorithm #1):
+    /// hop from the last unmapped channel and remap it on the used channels if it is not in the channel map
+    pub fn next_channel(&mut self) -> BleChannel {
+        let unmapped = (self.last_unmapped + self.ll.hop) % BLE_DATA_CHANNELS;
+        self.last_unmapped = unmapped;
+        self.event_counter = self.event_counter.wrapping_add(1);
+        let used = self.ll.used_channels();
+        let idx = if self.ll.chan_map & (1 << unmapped) != 0 || used == 0 {
+            unmapped
+        } else {
+            let remap = unmapped % used;
+            (0..BLE_DATA_CHANNELS)
+                .filter(|c| self.ll.chan_map & (1 << c) != 0)
+                .nth(remap as usize)
+                .unwrap_or(unmapped)
+        };
+        BleChannel(idx)
+    }
+}
+
+/// Send a connection request to an advertiser, using public addresses (TxAdd/RxAdd cleared)
+/// The connection starts at the end of this packet: see BleLlData::transmit_window
+pub async fn ble_send_connect_ind(lr2021: &mut Lr2021Stm32, target: u64, own: u64, ll: &BleLlData) -> Result<(), AppError> {
+    let len = 14 + BLE_LL_DATA_LEN;
+    let buffer = lr2021.buffer_mut();
+    buffer[0] = BleAdvType::ConnectInd as u8;
+    buffer[1] = (len - 2) as u8;
+    write_ble_addr(&mut buffer[2..8], own);
+    write_ble_addr(&mut buffer[8..14], target);
+    buffer[14..len].copy_from_slice(&ll.to_bytes());
+    lr2021.wr_tx_fifo(len).await?;
+    lr2021.set_ble_tx(len as u8).await?;
+    Ok(())
+}
+
+/// Start a connection event as central: tune on the next data channel
+/// and send an empty data PDU at the anchor point.
+/// For the first event, the anchor must be inside the transmit window (see BleLlData::transmit_window)
+/// The peripheral answers 150us after the end of the packet: RX must be started on TX done.
+/// Return the data channel used
+pub async fn ble_connection_event(lr2021: &mut Lr2021Stm32, conn: &mut BleConnection, anchor: Instant) -> Result<BleChannel, AppError> {
+    let chan = conn.next_channel();
+    lr2021.set_chip_mode(ChipMode::Fs).await?;
+    lr2021.set_rf(chan.frequency_hz()).await?;
+    lr2021.set_ble_params(false, ChannelType::Data, chan.whitening_init(), conn.ll.crc_init, conn.ll.access_addr).await?;
+    // Empty PDU: LLID = 1 (continuation), NESN = SN = 0, no payload
+    lr2021.buffer_mut()[..2].copy_from_slice(&[0x01, 0x00]);
+    lr2021.wr_tx_fifo(2).await?;
+    Timer::at(anchor).await;
+    lr2021.set_ble_tx(2).await?;
+    Ok(chan)
+}
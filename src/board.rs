@@ -1,29 +1,86 @@
-use defmt::{info, Format};
+use defmt::{info, warn, Format};
 use embassy_executor::Spawner;
 use embassy_stm32::{
     Peripherals, bind_interrupts, exti::ExtiInput, gpio::{Level, Output, Pull, Speed}, mode::Async, spi::{Config as SpiConfig, Spi}, time::Hertz, usart::{Config as UartConfig, Uart}
 };
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal, watch::{Receiver, Watch}};
 use embassy_time::{with_timeout, Duration, Timer};
-use lr2021::{system::{DioFunc, DioNum, PullDrive}, BusyAsync, Lr2021};
+use lr2021::{system::DioNum, BusyAsync, Lr2021};
+
+use crate::radio_utils::{config_tx_trigger, reset_and_report};
 
 bind_interrupts!(struct UartIrqs {
     USART2 => embassy_stm32::usart::InterruptHandler<embassy_stm32::peripherals::USART2>;
 });
 
+#[cfg(not(feature = "spi-dma"))]
 pub type Lr2021Stm32 = Lr2021<Output<'static>,SpiWrapper, BusyAsync<ExtiInput<'static>>>;
-// pub type Lr2021Stm32 = Lr2021<Output<'static>,Spi<'static,Async>, BusyAsync<ExtiInput<'static>>>;
+#[cfg(feature = "spi-dma")]
+pub type Lr2021Stm32 = Lr2021<Output<'static>,Spi<'static,Async>, BusyAsync<ExtiInput<'static>>>;
+
+/// Board abstraction: provides the LR2021 driver, the IRQ line, the user button events and the leds
+/// Porting the demos to a new board only requires a new implementation of this trait.
+#[allow(async_fn_in_trait)]
+pub trait Board: Sized {
+    /// LR2021 driver instance
+    type Radio;
+    /// Input connected to the LR2021 IRQ DIO
+    type Irq;
+    /// Receiver of user button events
+    type Button;
+
+    /// Initialize the board peripherals, spawn the UI tasks and reset the LR2021
+    async fn init(spawner: &Spawner) -> Self;
+    /// Split the board into the radio driver and the IRQ input
+    fn into_radio(self) -> (Self::Radio, Self::Irq);
+    /// Get a receiver of user button events
+    fn get_button_evt() -> Self::Button;
+    /// Set the mode of a led
+    fn led_set(led: Led, mode: LedMode);
+    /// Set red led mode
+    fn led_red_set(mode: LedMode) {
+        Self::led_set(Led::RED, mode)
+    }
+    /// Set green led mode
+    fn led_green_set(mode: LedMode) {
+        Self::led_set(Led::GREEN, mode)
+    }
+}
+
+/// Maximum SPI clock supported by the LR2021
+pub const SPI_HZ_MAX: u32 = 16_000_000;
+
+/// Board configuration
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub struct BoardConfig {
+    /// SPI clock frequency: lower it for long wires (clamped to SPI_HZ_MAX)
+    pub spi_hz: u32,
+    /// Timings of the user button
+    pub button: ButtonConfig,
+}
+
+impl Default for BoardConfig {
+    fn default() -> Self {
+        Self {
+            spi_hz: 12_000_000,
+            button: ButtonConfig::default(),
+        }
+    }
+}
 
 pub struct BoardNucleoL476Rg {
     pub lr2021: Lr2021Stm32,
     pub irq: ExtiInput<'static>,
+    /// Connected to DIO8, configured as TX trigger (see radio_utils::schedule_tx_at)
     pub trigger_tx: Output<'static>,
-    pub uart: Uart<'static, Async>
+    pub uart: Uart<'static, Async>,
+    /// SPI clock frequency used
+    pub spi_hz: u32,
 }
 
 /// Generate event when the button is press with short (0) or long (1) duration
 type WatchButtonPress = Watch<CriticalSectionRawMutex, ButtonPressKind, 3>;
-type ButtonRcvr = Receiver<'static, CriticalSectionRawMutex, ButtonPressKind, 3>;
+pub type ButtonRcvr = Receiver<'static, CriticalSectionRawMutex, ButtonPressKind, 3>;
 pub static BUTTON_PRESS: WatchButtonPress = Watch::new();
 /// Led modes
 pub static LED_RED_MODE: SignalLedMode = Signal::new();
@@ -73,6 +130,11 @@ impl BoardNucleoL476Rg {
     // LEDRX | CN8 A4    | PC1
 
     pub async fn init(spawner: &Spawner) -> BoardNucleoL476Rg {
+        Self::init_with(spawner, BoardConfig::default()).await
+    }
+
+    /// Initialize the board with a custom configuration
+    pub async fn init_with(spawner: &Spawner, cfg: BoardConfig) -> BoardNucleoL476Rg {
         let p = stm32_init();
 
         // Leds & buttons
@@ -83,7 +145,7 @@ impl BoardNucleoL476Rg {
         // Start the tasks
         spawner.spawn(blink(led_red, &LED_RED_MODE)).unwrap();
         spawner.spawn(blink(led_green, &LED_GREEN_MODE)).unwrap();
-        spawner.spawn(user_intf(button, &BUTTON_PRESS)).unwrap();
+        spawner.spawn(user_intf(button, &BUTTON_PRESS, cfg.button)).unwrap();
         LED_RED_MODE.signal(LedMode::Off);
         LED_GREEN_MODE.signal(LedMode::Off);
 
@@ -101,36 +163,76 @@ impl BoardNucleoL476Rg {
 
         // SPI
         let mut spi_config = SpiConfig::default();
-        spi_config.frequency = Hertz(12_000_000);
+        let spi_hz = cfg.spi_hz.min(SPI_HZ_MAX);
+        if spi_hz != cfg.spi_hz {
+            warn!("SPI clock limited to {}Hz", spi_hz);
+        }
+        spi_config.frequency = Hertz(spi_hz);
+        #[cfg(not(feature = "spi-dma"))]
         let spi = SpiWrapper(Spi::new_blocking(p.SPI1, p.PA5, p.PA7, p.PA6, spi_config));
-        // let spi = Spi::new(
-        //     p.SPI1, p.PA5, p.PA7, p.PA6, p.DMA1_CH3, p.DMA1_CH2, spi_config,
-        // );
+        #[cfg(feature = "spi-dma")]
+        let spi = Spi::new(
+            p.SPI1, p.PA5, p.PA7, p.PA6, p.DMA1_CH3, p.DMA1_CH2, spi_config,
+        );
         let nss = Output::new(p.PA8, Level::High, Speed::VeryHigh);
 
         // Create driver and reset board
         let mut lr2021 = Lr2021::new(nreset, busy, spi, nss);
-        lr2021.reset().await.expect("Resetting chip !");
+        let reset_src = reset_and_report(&mut lr2021).await.expect("Resetting chip !");
+        info!("Reset source: {}", reset_src);
 
         // Configure DIO8 as a TX Trigger
-        lr2021.set_dio_function(DioNum::Dio8, DioFunc::TxTrigger, PullDrive::PullNone).await.expect("SetDioTxTrigger");
+        config_tx_trigger(&mut lr2021, DioNum::Dio8).await.expect("SetDioTxTrigger");
 
         // Check version
         let version = lr2021.get_version().await.expect("Reading firmware version !");
-        info!("FW Version {}", version);
-        BoardNucleoL476Rg{lr2021, irq, uart, trigger_tx}
+        info!("FW Version {} | SPI {}kHz", version, spi_hz / 1000);
+        BoardNucleoL476Rg{lr2021, irq, uart, trigger_tx, spi_hz}
     }
 
     pub fn get_button_evt() -> ButtonRcvr {
         BUTTON_PRESS.receiver().unwrap()
     }
 
+    /// Set the mode of a led
+    /// The Nucleo led (LD2 on PA5) is shared with the SPI clock and cannot be used: it is ignored
+    pub fn led_set(led: Led, mode: LedMode) {
+        match led.signal() {
+            Some(signal) => signal.signal(mode),
+            None => warn!("{} not available on this board", led),
+        }
+    }
+
+    /// Set red led mode (alias of led_set with Led::RED)
     pub fn led_red_set(mode: LedMode) {
-        LED_RED_MODE.signal(mode)
+        Self::led_set(Led::RED, mode)
     }
 
+    /// Set green led mode (alias of led_set with Led::GREEN)
     pub fn led_green_set(mode: LedMode) {
-        LED_GREEN_MODE.signal(mode)
+        Self::led_set(Led::GREEN, mode)
+    }
+}
+
+impl Board for BoardNucleoL476Rg {
+    type Radio = Lr2021Stm32;
+    type Irq = ExtiInput<'static>;
+    type Button = ButtonRcvr;
+
+    async fn init(spawner: &Spawner) -> Self {
+        BoardNucleoL476Rg::init(spawner).await
+    }
+
+    fn into_radio(self) -> (Self::Radio, Self::Irq) {
+        (self.lr2021, self.irq)
+    }
+
+    fn get_button_evt() -> Self::Button {
+        BoardNucleoL476Rg::get_button_evt()
+    }
+
+    fn led_set(led: Led, mode: LedMode) {
+        BoardNucleoL476Rg::led_set(led, mode)
     }
 }
 
@@ -178,7 +280,9 @@ impl From<u8> for BoardRole {
 pub enum ButtonPressKind {
     Short,
     Double,
-    Long
+    Long,
+    /// Emitted periodically while the button is held after a long press (see ButtonConfig::long_repeat)
+    LongRepeat,
 }
 
 impl ButtonPressKind {
@@ -187,21 +291,53 @@ impl ButtonPressKind {
     }
 }
 
+/// Timings of the user button
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub struct ButtonConfig {
+    /// Debounce delay after the button is pressed
+    pub debounce: Duration,
+    /// Minimum duration of a long press
+    pub long_press: Duration,
+    /// Maximum delay between two presses of a double press
+    pub double_press: Duration,
+    /// Period of the LongRepeat events while the button is held after a long press (None to disable)
+    pub long_repeat: Option<Duration>,
+}
+
+impl Default for ButtonConfig {
+    fn default() -> Self {
+        Self {
+            debounce: Duration::from_millis(5),
+            long_press: Duration::from_millis(500),
+            double_press: Duration::from_millis(150),
+            long_repeat: None,
+        }
+    }
+}
+
+impl ButtonConfig {
+    /// Enable the LongRepeat events with a given period
+    pub fn with_long_repeat(mut self, period: Duration) -> Self {
+        self.long_repeat = Some(period);
+        self
+    }
+}
+
 /// Task to handle the user interface:
 ///   - a long press change the board mode (TX or RX)
 ///   - a short press either send a packet (TX mode) or clear the RX stat (RX mode)
 #[embassy_executor::task]
-pub async fn user_intf(mut button: ExtiInput<'static>, watch: &'static WatchButtonPress) {
+pub async fn user_intf(mut button: ExtiInput<'static>, watch: &'static WatchButtonPress, cfg: ButtonConfig) {
     let s = watch.sender();
     loop {
         button.wait_for_falling_edge().await;
         // Small wait to debounce button press
-        Timer::after_millis(5).await;
+        Timer::after(cfg.debounce).await;
         // Determine if this is a short or long press
-        let k = match with_timeout(Duration::from_millis(500), button.wait_for_high()).await {
+        let k = match with_timeout(cfg.long_press, button.wait_for_high()).await {
             // Short press -> check for another press shortly after
             Ok(_) => {
-                match with_timeout(Duration::from_millis(150), button.wait_for_falling_edge()).await {
+                match with_timeout(cfg.double_press, button.wait_for_falling_edge()).await {
                     Ok(_) => ButtonPressKind::Double,
                     Err(_) => ButtonPressKind::Short,
                 }
@@ -209,11 +345,44 @@ pub async fn user_intf(mut button: ExtiInput<'static>, watch: &'static WatchButt
             // Long press
             Err(_) => ButtonPressKind::Long,
         };
-        s.send(k)
+        s.send(k);
+        // Repeat events until the button is released
+        if let (ButtonPressKind::Long, Some(period)) = (k, cfg.long_repeat) {
+            while with_timeout(period, button.wait_for_high()).await.is_err() {
+                s.send(ButtonPressKind::LongRepeat);
+            }
+        }
     }
 }
 
 
+/// Leds of the board
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub enum Led {
+    /// User led of the Nucleo board (LD2)
+    Nucleo,
+    /// Green led of the LR2021 module (LEDTX on PC0)
+    ModuleTx,
+    /// Red led of the LR2021 module (LEDRX on PC1)
+    ModuleRx,
+}
+
+impl Led {
+    /// Red led used by the demos
+    pub const RED: Led = Led::ModuleRx;
+    /// Green led used by the demos
+    pub const GREEN: Led = Led::ModuleTx;
+
+    /// Signal controlling the blink task of the led, None if the led is not available
+    pub fn signal(&self) -> Option<&'static SignalLedMode> {
+        match self {
+            Led::Nucleo => None,
+            Led::ModuleTx => Some(&LED_GREEN_MODE),
+            Led::ModuleRx => Some(&LED_RED_MODE),
+        }
+    }
+}
+
 /// Led Mode
 #[derive(Debug, Clone, Copy, Format, PartialEq)]
 pub enum LedMode {
@@ -266,7 +435,7 @@ impl From<u8> for LedMode {
 
 pub type SignalLedMode = Signal<CriticalSectionRawMutex, LedMode>;
 
-/// Task pool to control up to 3 leds (nucleo + 2 on the LR2021 module)
+/// Task pool to control the 2 leds of the LR2021 module (the Nucleo led shares its pin with the SPI clock)
 #[embassy_executor::task(pool_size = 2)]
 pub async fn blink(mut led: Output<'static>, signal: &'static SignalLedMode) {
     let mut burst_cnt : u8 = 0;
@@ -314,6 +483,7 @@ pub async fn blink(mut led: Output<'static>, signal: &'static SignalLedMode) {
 
 
 // Wrapper around blocking SPI to use the non-DMA SPI with the LR2021 driver
+// Used by default: enable feature spi-dma to use the async SPI with DMA instead
 pub struct SpiWrapper(pub Spi<'static,embassy_stm32::mode::Blocking>);
 
 impl embedded_hal::spi::ErrorType for SpiWrapper {
@@ -0,0 +1,82 @@
+use defmt::Format;
+use lr2021::{bpsk::{set_bpsk_modulation_params_cmd, set_bpsk_packet_params_cmd}, Lr2021Error, PulseShape};
+
+use crate::{board::Lr2021Stm32, error::AppError};
+
+/// BPSK modulation parameters (see set_bpsk_modulation)
+#[derive(Debug, Clone, Copy)]
+pub struct BpskModulation {
+    /// Bitrate in bit/s (Sigfox uplink: 100 or 600)
+    pub bitrate: u32,
+    pub pulse_shape: PulseShape,
+    /// Differential encoding (DBPSK): the phase changes on each 0
+    pub diff: bool,
+    /// Initial state of the differential encoder
+    pub diff_init: bool,
+    /// Add a parity bit at the end of the differential encoding
+    pub diff_parity: bool,
+}
+
+impl BpskModulation {
+    /// Sigfox-style DBPSK uplink at the given bitrate
+    pub fn dbpsk(bitrate: u32) -> Self {
+        Self {
+            bitrate,
+            pulse_shape: PulseShape::Bt0p5,
+            diff: true,
+            diff_init: false,
+            diff_parity: false,
+        }
+    }
+}
+
+/// BPSK ramp shaping around the frame (see set_bpsk_packet)
+/// The power ramps at the start and end of the frame set the spectrum of the transition,
+/// and must be adjusted with the bitrate to meet the spectral mask.
+/// Values are in the raw unit of the chip.
+#[derive(Debug, Clone, Copy, Format, PartialEq, Default)]
+pub struct BpskRamp {
+    /// Delay before the ramp-up
+    pub up_delay: u16,
+    /// Delay before the ramp-down
+    pub down_delay: u16,
+    /// Duration of the ramp-up
+    pub up_len: u16,
+    /// Duration of the ramp-down
+    pub down_len: u16,
+}
+
+/// Configure the BPSK modulation
+/// Packet type must be set to BPSK
+pub async fn set_bpsk_modulation(lr2021: &mut Lr2021Stm32, modulation: &BpskModulation) -> Result<(), Lr2021Error> {
+    let req = set_bpsk_modulation_params_cmd(
+        modulation.bitrate,
+        modulation.pulse_shape,
+        modulation.diff,
+        modulation.diff_init,
+        modulation.diff_parity,
+    );
+    lr2021.cmd_wr(&req).await
+}
+
+/// Configure the BPSK frame: payload length in bytes and ramp shaping
+/// Packet type must be set to BPSK
+pub async fn set_bpsk_packet(lr2021: &mut Lr2021Stm32, pld_len: u8, ramp: &BpskRamp) -> Result<(), Lr2021Error> {
+    let req = set_bpsk_packet_params_cmd(pld_len, ramp.up_delay, ramp.down_delay, ramp.up_len, ramp.down_len);
+    lr2021.cmd_wr(&req).await
+}
+
+/// Send a payload in BPSK (TX only: the chip has no BPSK receiver)
+/// The payload is written in the TX FIFO, the packet length updated and the transmission started.
+pub async fn bpsk_tx(lr2021: &mut Lr2021Stm32, payload: &[u8], ramp: &BpskRamp) -> Result<(), AppError> {
+    let len = payload.len();
+    let buffer = lr2021.buffer_mut();
+    if len > buffer.len() || len > u8::MAX as usize {
+        return Err(AppError::PayloadTooLong(len));
+    }
+    buffer[..len].copy_from_slice(payload);
+    lr2021.wr_tx_fifo(len).await?;
+    set_bpsk_packet(lr2021, len as u8, ramp).await?;
+    lr2021.set_tx(0).await?;
+    Ok(())
+}
@@ -0,0 +1,37 @@
+use defmt::Format;
+use lr2021::Lr2021Error;
+
+/// Errors reported by the application helpers
+#[derive(Debug, Format)]
+pub enum AppError {
+    /// Error from the driver (SPI, busy timeout, command error, ...)
+    Radio(Lr2021Error),
+    /// Packet received with a CRC error, with the number of bytes read
+    CrcError(usize),
+    /// RX FIFO overflow, with the number of bytes read before the overflow
+    FifoOverflow(usize),
+    /// Destination buffer too small, with the number of bytes read
+    BufferTooSmall(usize),
+    /// Payload larger than allowed by the protocol, with the length requested
+    PayloadTooLong(usize),
+    /// Invalid list of settings, with the index of the first offending entry
+    InvalidConfig(usize),
+    /// Parameter outside its allowed range, with the value rejected
+    OutOfRange(u32),
+    /// RX path not matching the RF frequency, with the frequency in Hz
+    RxPathMismatch(u32),
+    /// Mode or sub-band not part of the selected region or band plan (raw value)
+    UnsupportedMode(u8),
+    /// Operation not supported with the current packet type (raw value)
+    UnsupportedPacketType(u8),
+    /// Calibration failed, with the calibration failure flags reported by the chip
+    CalibError(u32),
+    /// RX frequency not covered by the front-end calibration, with the frequency in Hz
+    NotCalibrated(u32),
+}
+
+impl From<Lr2021Error> for AppError {
+    fn from(e: Lr2021Error) -> Self {
+        AppError::Radio(e)
+    }
+}
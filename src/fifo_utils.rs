@@ -0,0 +1,249 @@
+use defmt::Format;
+use embassy_stm32::exti::ExtiInput;
+use lr2021::{
+    status::{Intr, IRQ_MASK_CRC_ERROR, IRQ_MASK_RX_DONE, IRQ_MASK_RX_FIFO},
+    system::{clear_fifo_irq_flags_cmd, config_fifo_irq_cmd, get_and_clear_fifo_irq_flags_req, get_fifo_irq_flags_req, AndClearFifoIrqFlagsRsp, ChipMode, DioNum, FifoIrqFlagsRsp},
+    Lr2021Error,
+};
+
+use crate::{board::Lr2021Stm32, error::AppError, radio_utils::set_rx_continuous};
+
+/// FIFO interrupt flags (same bit position for RX and TX FIFO)
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub enum FifoFlag {
+    /// FIFO level reached 0 (TX: all data sent, RX: all data read)
+    Empty = 0x01,
+    /// FIFO level reached the FIFO size
+    Full = 0x02,
+    /// FIFO level went below the low threshold
+    ThrLow = 0x04,
+    /// FIFO level went above the high threshold
+    ThrHigh = 0x08,
+    /// Write while FIFO is full (RX: data received with the FIFO full were lost)
+    Overflow = 0x10,
+    /// Read while FIFO is empty (TX: the modem needed data not yet written)
+    Underflow = 0x20,
+}
+
+impl FifoFlag {
+    /// Bit mask of the flag
+    pub const fn mask(self) -> u8 {
+        self as u8
+    }
+
+    /// Combine a list of flags into a bit mask
+    pub fn to_mask(flags: &[FifoFlag]) -> u8 {
+        flags.iter().fold(0, |m, f| m | f.mask())
+    }
+}
+
+/// RX/TX FIFO interrupt flags, as read from the chip (see FifoFlag for the bits)
+#[derive(Debug, Clone, Copy, Format, PartialEq, Default)]
+pub struct FifoFlags {
+    /// Raw RX FIFO flags
+    pub rx: u8,
+    /// Raw TX FIFO flags
+    pub tx: u8,
+}
+
+impl FifoFlags {
+    /// Flags from the raw RX and TX FIFO flags
+    pub fn from_raw(rx: u8, tx: u8) -> Self {
+        Self {rx, tx}
+    }
+
+    /// True if the flag is set for the RX FIFO
+    pub fn rx_contains(&self, flag: FifoFlag) -> bool {
+        self.rx & flag.mask() != 0
+    }
+
+    /// True if the flag is set for the TX FIFO
+    pub fn tx_contains(&self, flag: FifoFlag) -> bool {
+        self.tx & flag.mask() != 0
+    }
+
+    /// RX FIFO full: the next received bytes will be lost if the FIFO is not read
+    pub fn rx_full(&self) -> bool {
+        self.rx_contains(FifoFlag::Full)
+    }
+
+    /// RX FIFO level above the high threshold: data ready to be read
+    pub fn rx_threshold(&self) -> bool {
+        self.rx_contains(FifoFlag::ThrHigh)
+    }
+
+    /// TX FIFO empty: all data written were sent
+    pub fn tx_empty(&self) -> bool {
+        self.tx_contains(FifoFlag::Empty)
+    }
+
+    /// TX FIFO level below the low threshold: room to write more data
+    pub fn tx_threshold(&self) -> bool {
+        self.tx_contains(FifoFlag::ThrLow)
+    }
+
+    /// Overflow on either FIFO (RX: received data lost, TX: data written while full)
+    pub fn overflow(&self) -> bool {
+        self.rx_contains(FifoFlag::Overflow) || self.tx_contains(FifoFlag::Overflow)
+    }
+
+    /// Underflow on either FIFO (TX: the modem needed data not yet written, RX: read while empty)
+    pub fn underflow(&self) -> bool {
+        self.rx_contains(FifoFlag::Underflow) || self.tx_contains(FifoFlag::Underflow)
+    }
+
+    /// True if any error (overflow/underflow) is reported on either FIFO
+    pub fn has_error(&self) -> bool {
+        let err = FifoFlag::to_mask(&[FifoFlag::Overflow, FifoFlag::Underflow]);
+        (self.rx | self.tx) & err != 0
+    }
+}
+
+/// Read the FIFO interrupt flags, clearing them when clear is true
+pub async fn fifo_irq_flags(lr2021: &mut Lr2021Stm32, clear: bool) -> Result<FifoFlags, Lr2021Error> {
+    if clear {
+        let mut rsp = AndClearFifoIrqFlagsRsp::new();
+        lr2021.cmd_rd(&get_and_clear_fifo_irq_flags_req(), rsp.as_mut()).await?;
+        Ok(FifoFlags::from_raw(rsp.rx_fifo_flags(), rsp.tx_fifo_flags()))
+    } else {
+        let mut rsp = FifoIrqFlagsRsp::new();
+        lr2021.cmd_rd(&get_fifo_irq_flags_req(), rsp.as_mut()).await?;
+        Ok(FifoFlags::from_raw(rsp.rx_fifo_flags(), rsp.tx_fifo_flags()))
+    }
+}
+
+/// Clear only the listed RX and TX FIFO flags, leaving the others pending
+/// Typically used on the threshold flag while streaming a packet larger than the FIFO
+pub async fn clear_fifo_flags(lr2021: &mut Lr2021Stm32, rx: &[FifoFlag], tx: &[FifoFlag]) -> Result<(), Lr2021Error> {
+    let req = clear_fifo_irq_flags_cmd(FifoFlag::to_mask(rx), FifoFlag::to_mask(tx));
+    lr2021.cmd_wr(&req).await
+}
+
+/// FIFO interrupt configuration: enabled flags and thresholds (in bytes) for RX and TX FIFO
+#[derive(Debug, Clone, Copy, Format, PartialEq, Default)]
+pub struct FifoIrqConfig {
+    /// RX FIFO interrupt enables (combination of FifoFlag masks)
+    pub rx_en: u8,
+    /// TX FIFO interrupt enables (combination of FifoFlag masks)
+    pub tx_en: u8,
+    /// RX FIFO high threshold in bytes (ThrHigh set when the level goes above)
+    pub rx_high: u16,
+    /// RX FIFO low threshold in bytes (ThrLow set when the level goes below)
+    pub rx_low: u16,
+    /// TX FIFO high threshold in bytes (ThrHigh set when the level goes above)
+    pub tx_high: u16,
+    /// TX FIFO low threshold in bytes (ThrLow set when the level goes below)
+    pub tx_low: u16,
+}
+
+impl FifoIrqConfig {
+    /// Configuration with all FIFO interrupts disabled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enable RX FIFO interrupt when level goes above the threshold
+    pub fn with_rx_high(mut self, thr: u16) -> Self {
+        self.rx_high = thr;
+        self.rx_en |= FifoFlag::ThrHigh.mask();
+        self
+    }
+
+    /// Enable RX FIFO interrupt when level goes below the threshold
+    pub fn with_rx_low(mut self, thr: u16) -> Self {
+        self.rx_low = thr;
+        self.rx_en |= FifoFlag::ThrLow.mask();
+        self
+    }
+
+    /// Enable TX FIFO interrupt when level goes above the threshold
+    pub fn with_tx_high(mut self, thr: u16) -> Self {
+        self.tx_high = thr;
+        self.tx_en |= FifoFlag::ThrHigh.mask();
+        self
+    }
+
+    /// Enable TX FIFO interrupt when level goes below the threshold
+    pub fn with_tx_low(mut self, thr: u16) -> Self {
+        self.tx_low = thr;
+        self.tx_en |= FifoFlag::ThrLow.mask();
+        self
+    }
+
+    /// Enable additional RX FIFO interrupts (full, overflow, ...)
+    pub fn with_rx_irq(mut self, flags: &[FifoFlag]) -> Self {
+        self.rx_en |= FifoFlag::to_mask(flags);
+        self
+    }
+
+    /// Enable additional TX FIFO interrupts (empty, underflow, ...)
+    pub fn with_tx_irq(mut self, flags: &[FifoFlag]) -> Self {
+        self.tx_en |= FifoFlag::to_mask(flags);
+        self
+    }
+}
+
+/// Configure FIFO interrupts
+pub async fn config_fifo_irq(lr2021: &mut Lr2021Stm32, cfg: &FifoIrqConfig) -> Result<(), Lr2021Error> {
+    // Arguments of lr2021::system::config_fifo_irq_cmd: rx_en, tx_en, then the thresholds
+    // in the order rx_high, tx_low, rx_low, tx_high (not grouped by FIFO)
+    let req = config_fifo_irq_cmd(cfg.rx_en, cfg.tx_en, cfg.rx_high, cfg.tx_low, cfg.rx_low, cfg.tx_high);
+    lr2021.cmd_wr(&req).await
+}
+
+/// RX FIFO level triggering a read during a large packet reception
+const RX_CHUNK_THR: u16 = 128;
+
+/// Read the content of the RX FIFO into dst starting at offset
+/// Return the new offset, or BufferTooSmall with the number of bytes written in dst when the FIFO does not fit
+async fn drain_rx_fifo(lr2021: &mut Lr2021Stm32, dst: &mut [u8], offset: usize) -> Result<usize, AppError> {
+    let lvl = lr2021.get_rx_fifo_lvl().await? as usize;
+    if lvl == 0 {
+        return Ok(offset);
+    }
+    let len = lvl.min(dst.len() - offset);
+    if len < lvl {
+        lr2021.rd_rx_fifo_to(&mut dst[offset..]).await?;
+        return Err(AppError::BufferTooSmall(offset + len));
+    }
+    lr2021.rd_rx_fifo_to(&mut dst[offset..offset+len]).await?;
+    Ok(offset + len)
+}
+
+/// Receive a packet larger than the FIFO by draining it each time it reaches a threshold
+/// The chip must be configured for the reception: this function sets the FIFO and DIO interrupts,
+/// starts a continuous RX and waits on the irq pin connected to dio until RX done.
+/// On error the chip is put back in standby RC, stopping the reception.
+/// Return the number of bytes received, or the number of bytes read when an error occurs.
+pub async fn receive_large(lr2021: &mut Lr2021Stm32, irq: &mut ExtiInput<'static>, dst: &mut [u8], dio: DioNum) -> Result<usize, AppError> {
+    let res = receive_large_chunks(lr2021, irq, dst, dio).await;
+    if res.is_err() {
+        lr2021.set_chip_mode(ChipMode::StandbyRc).await?;
+    }
+    res
+}
+
+/// Reception loop of receive_large, leaving the chip in RX on error
+async fn receive_large_chunks(lr2021: &mut Lr2021Stm32, irq: &mut ExtiInput<'static>, dst: &mut [u8], dio: DioNum) -> Result<usize, AppError> {
+    let cfg = FifoIrqConfig::new()
+        .with_rx_high(RX_CHUNK_THR)
+        .with_rx_irq(&[FifoFlag::Overflow]);
+    config_fifo_irq(lr2021, &cfg).await?;
+    lr2021.set_dio_irq(dio, Intr::new(IRQ_MASK_RX_FIFO|IRQ_MASK_RX_DONE|IRQ_MASK_CRC_ERROR)).await?;
+    lr2021.clear_rx_fifo().await?;
+    set_rx_continuous(lr2021).await?;
+
+    let mut nb = 0;
+    loop {
+        irq.wait_for_high().await;
+        let intr = lr2021.get_and_clear_irq().await?;
+        let flags = fifo_irq_flags(lr2021, true).await?;
+        nb = drain_rx_fifo(lr2021, dst, nb).await?;
+        if flags.rx_contains(FifoFlag::Overflow) {
+            return Err(AppError::FifoOverflow(nb));
+        }
+        if intr.rx_done() {
+            return if intr.crc_error() {Err(AppError::CrcError(nb))} else {Ok(nb)};
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use defmt::Format;
+use lr2021::{fsk::{AddrComp, Crc, FskPktFormat, PblLenDetect, PldLenUnit}, status::Intr, system::DioNum, Lr2021Error};
+
+use crate::{board::Lr2021Stm32, error::AppError, intr_utils::IntrFlag};
+
+/// DC-free encoding of the FSK payload
+///
+/// The packet parameters command carries it on the low nibble of the byte holding the CRC (high nibble):
+///  - 0: no encoding
+///  - 1: whitening (the only encoding selected by the driver when dc_free is true)
+///
+/// Manchester coding is not available in FSK packets, only in OOK.
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub enum DcFree {
+    Off = 0,
+    Whitening = 1,
+}
+
+impl DcFree {
+    /// Value of the DC-free nibble in the packet parameters command
+    pub fn nibble(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        *self != DcFree::Off
+    }
+}
+
+/// FSK packet parameters with named fields (see set_fsk_packet_params)
+#[derive(Debug, Clone, Copy)]
+pub struct FskPacketParams {
+    /// Preamble length in TX (in bits)
+    pub pbl_len_tx: u16,
+    /// Minimum preamble length detected in RX (see set_preamble_detect)
+    pub pbl_len_detect: PblLenDetect,
+    /// Allow a long preamble (length on 16 bits)
+    pub pbl_long: bool,
+    /// Unit of the payload length (bytes or bits)
+    pub pld_len_unit: PldLenUnit,
+    /// Address comparison mode
+    pub addr_comp: AddrComp,
+    /// Packet format: fixed or variable length
+    pub pkt_format: FskPktFormat,
+    /// Payload length: fixed length or maximum length with a variable format
+    pub pld_len: u8,
+    /// CRC size
+    pub crc: Crc,
+    /// DC-free encoding
+    pub dc_free: DcFree,
+}
+
+/// 8 bits preamble, variable length up to 255 bytes, 2-byte CRC and whitening enabled
+impl Default for FskPacketParams {
+    fn default() -> Self {
+        Self {
+            pbl_len_tx: 8,
+            pbl_len_detect: PblLenDetect::None,
+            pbl_long: false,
+            pld_len_unit: PldLenUnit::Bytes,
+            addr_comp: AddrComp::Off,
+            pkt_format: FskPktFormat::Variable8bit,
+            pld_len: 255,
+            crc: Crc::Crc2Byte,
+            dc_free: DcFree::Whitening,
+        }
+    }
+}
+
+/// Configure the FSK packet parameters
+/// Packet type must be set to FSK
+pub async fn set_fsk_packet_params(lr2021: &mut Lr2021Stm32, params: &FskPacketParams) -> Result<(), Lr2021Error> {
+    lr2021.set_fsk_packet(
+        params.pbl_len_tx,
+        params.pbl_len_detect,
+        params.pbl_long,
+        params.pld_len_unit,
+        params.addr_comp,
+        params.pkt_format,
+        params.pld_len,
+        params.crc,
+        params.dc_free.is_enabled(),
+    ).await
+}
+
+/// Set the minimum preamble length detected in RX, keeping the PreambleDetected interrupt coherent with it.
+/// With PblLenDetect::None the detection is done on the syncword and the PreambleDetected interrupt never fires:
+/// it is then masked on the DIO, and enabled for any other length.
+/// The DIO configuration cannot be read back, so irq holds the current mask of the DIO and is updated.
+/// Return OutOfRange (with the length in bits) if the detection length is longer than the TX preamble,
+/// since a peer using the same parameters would never be detected.
+pub async fn set_preamble_detect(lr2021: &mut Lr2021Stm32, params: &mut FskPacketParams, dio: DioNum, irq: &mut Intr, len: PblLenDetect) -> Result<(), AppError> {
+    let len_bits = len as u16;
+    if len_bits > params.pbl_len_tx {
+        return Err(AppError::OutOfRange(len_bits as u32));
+    }
+    params.pbl_len_detect = len;
+    set_fsk_packet_params(lr2021, params).await?;
+    let mask = IntrFlag::PreambleDetected.mask();
+    *irq = if len_bits == 0 {Intr::new(irq.value() & !mask)} else {Intr::new(irq.value() | mask)};
+    lr2021.set_dio_irq(dio, *irq).await?;
+    Ok(())
+}
@@ -0,0 +1,199 @@
+use core::ops::{BitAnd, BitOr, BitOrAssign};
+
+use defmt::Format;
+use embassy_time::{Duration, Instant, Timer};
+use lr2021::{status::*, system::{clear_irq_cmd, ErrorsRsp}, Lr2021Error};
+
+use crate::board::Lr2021Stm32;
+
+/// Interrupt flags, with value equal to the interrupt mask
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+#[repr(u32)]
+pub enum IntrFlag {
+    RxFifo = IRQ_MASK_RX_FIFO,
+    TxFifo = IRQ_MASK_TX_FIFO,
+    RngReqVld = IRQ_MASK_RNG_REQ_VLD,
+    TxTimestamp = IRQ_MASK_TX_TIMESTAMP,
+    RxTimestamp = IRQ_MASK_RX_TIMESTAMP,
+    PreambleDetected = IRQ_MASK_PREAMBLE_DETECTED,
+    HeaderValid = IRQ_MASK_HEADER_VALID,
+    CadDetected = IRQ_MASK_CAD_DETECTED,
+    LoraHdrTimestamp = IRQ_MASK_LORA_HDR_TIMESTAMP,
+    HeaderErr = IRQ_MASK_HEADER_ERR,
+    Eol = IRQ_MASK_EOL,
+    Pa = IRQ_MASK_PA,
+    LoraTxRxHop = IRQ_MASK_LORA_TX_RX_HOP,
+    SyncFail = IRQ_MASK_SYNC_FAIL,
+    LoraSymbolEnd = IRQ_MASK_LORA_SYMBOL_END,
+    LoraTimestampStat = IRQ_MASK_LORA_TIMESTAMP_STAT,
+    Error = IRQ_MASK_ERROR,
+    Cmd = IRQ_MASK_CMD,
+    RxDone = IRQ_MASK_RX_DONE,
+    TxDone = IRQ_MASK_TX_DONE,
+    CadDone = IRQ_MASK_CAD_DONE,
+    Timeout = IRQ_MASK_TIMEOUT,
+    CrcError = IRQ_MASK_CRC_ERROR,
+    LenError = IRQ_MASK_LEN_ERROR,
+    AddrError = IRQ_MASK_ADDR_ERROR,
+    Fhss = IRQ_MASK_FHSS,
+    InterPacket1 = IRQ_MASK_INTER_PACKET1,
+    InterPacket2 = IRQ_MASK_INTER_PACKET2,
+    RngRespDone = IRQ_MASK_RNG_RESP_DONE,
+    RngReqDis = IRQ_MASK_RNG_REQ_DIS,
+    RngExchVld = IRQ_MASK_RNG_EXCH_VLD,
+    RngTimeout = IRQ_MASK_RNG_TIMEOUT,
+}
+
+/// All interrupt flags, ordered by bit position
+pub const INTR_FLAGS: [IntrFlag; 32] = [
+    IntrFlag::RxFifo,
+    IntrFlag::TxFifo,
+    IntrFlag::RngReqVld,
+    IntrFlag::TxTimestamp,
+    IntrFlag::RxTimestamp,
+    IntrFlag::PreambleDetected,
+    IntrFlag::HeaderValid,
+    IntrFlag::CadDetected,
+    IntrFlag::LoraHdrTimestamp,
+    IntrFlag::HeaderErr,
+    IntrFlag::Eol,
+    IntrFlag::Pa,
+    IntrFlag::LoraTxRxHop,
+    IntrFlag::SyncFail,
+    IntrFlag::LoraSymbolEnd,
+    IntrFlag::LoraTimestampStat,
+    IntrFlag::Error,
+    IntrFlag::Cmd,
+    IntrFlag::RxDone,
+    IntrFlag::TxDone,
+    IntrFlag::CadDone,
+    IntrFlag::Timeout,
+    IntrFlag::CrcError,
+    IntrFlag::LenError,
+    IntrFlag::AddrError,
+    IntrFlag::Fhss,
+    IntrFlag::InterPacket1,
+    IntrFlag::InterPacket2,
+    IntrFlag::RngRespDone,
+    IntrFlag::RngReqDis,
+    IntrFlag::RngExchVld,
+    IntrFlag::RngTimeout,
+];
+
+impl IntrFlag {
+    /// Interrupt mask of the flag
+    pub fn mask(&self) -> u32 {
+        *self as u32
+    }
+}
+
+/// Extension of the interrupt status
+pub trait IntrExt {
+    /// Iterate over all flags set
+    fn iter(&self) -> impl Iterator<Item = IntrFlag>;
+    /// Check if a flag is set
+    fn contains(&self, flag: IntrFlag) -> bool;
+}
+
+impl IntrExt for Intr {
+    fn iter(&self) -> impl Iterator<Item = IntrFlag> {
+        let value = self.value();
+        INTR_FLAGS.iter()
+            .copied()
+            .filter(move |f| (value & f.mask()) != 0)
+    }
+
+    fn contains(&self, flag: IntrFlag) -> bool {
+        (self.value() & flag.mask()) != 0
+    }
+}
+
+impl From<IntrFlag> for Intr {
+    fn from(flag: IntrFlag) -> Self {
+        Intr::new(flag.mask())
+    }
+}
+
+/// Combine two flags into an interrupt mask: IntrFlag::RxDone | IntrFlag::TxDone
+impl BitOr for IntrFlag {
+    type Output = Intr;
+    fn bitor(self, rhs: IntrFlag) -> Intr {
+        Intr::new(self.mask() | rhs.mask())
+    }
+}
+
+/// Add a flag to an interrupt mask
+impl BitOr<IntrFlag> for Intr {
+    type Output = Intr;
+    fn bitor(self, rhs: IntrFlag) -> Intr {
+        Intr::new(self.value() | rhs.mask())
+    }
+}
+
+/// Keep only one flag of an interrupt mask
+impl BitAnd<IntrFlag> for Intr {
+    type Output = Intr;
+    fn bitand(self, rhs: IntrFlag) -> Intr {
+        Intr::new(self.value() & rhs.mask())
+    }
+}
+
+impl BitOrAssign<IntrFlag> for Intr {
+    fn bitor_assign(&mut self, rhs: IntrFlag) {
+        *self = Intr::new(self.value() | rhs.mask());
+    }
+}
+
+/// Read the pending interrupts without clearing them
+pub async fn get_irq(lr2021: &mut Lr2021Stm32) -> Result<Intr, Lr2021Error> {
+    let (_, intr) = lr2021.get_status().await?;
+    Ok(intr)
+}
+
+/// Clear only the interrupts in mask, e.g. after handling them
+/// With get_and_clear_irq, an interrupt raised between the read and the clear is lost.
+/// The DIO line stays high while any interrupt mapped on it is pending: with an edge-triggered input,
+/// clearing only what was handled and checking get_irq again before waiting for the next edge
+/// ensures no interrupt is left pending without a new edge to wake up the handler.
+pub async fn clear_irq(lr2021: &mut Lr2021Stm32, mask: Intr) -> Result<(), Lr2021Error> {
+    lr2021.cmd_wr(&clear_irq_cmd(mask.value())).await
+}
+
+/// Minimum and maximum delay between two status polls
+const POLL_DELAY_MIN: Duration = Duration::from_micros(100);
+const POLL_DELAY_MAX: Duration = Duration::from_millis(5);
+
+/// Wait for one of the interrupts in mask by polling the chip status, for boards without a DIO connected to an EXTI line
+/// The delay between polls increases progressively to avoid saturating the SPI bus.
+/// Return the interrupts of mask that are set, or an empty Intr on timeout.
+/// Only the interrupts in mask are cleared (see clear_irq): the others stay pending for their own handler.
+pub async fn wait_irq(lr2021: &mut Lr2021Stm32, mask: Intr, timeout: Duration) -> Result<Intr, Lr2021Error> {
+    let start = Instant::now();
+    let mut delay = POLL_DELAY_MIN;
+    loop {
+        let (status, intr) = lr2021.get_status().await?;
+        let intr = Intr::new(intr.value() & mask.value());
+        if status.irq() && intr.value() != 0 {
+            clear_irq(lr2021, mask).await?;
+            return Ok(intr);
+        }
+        if start.elapsed() >= timeout {
+            return Ok(Intr::new(0));
+        }
+        Timer::after(delay).await;
+        delay = (delay * 2).min(POLL_DELAY_MAX);
+    }
+}
+
+/// Fetch and clear the error flags when the CMD interrupt is set in intr, return None otherwise
+/// The status returned on each SPI transaction only reports the result of the previous command,
+/// and is easy to miss when commands are chained. The CMD interrupt is latched on any command error
+/// until cleared, so checking it with the other interrupts catches errors that would otherwise be silent.
+pub async fn handle_cmd_irq(lr2021: &mut Lr2021Stm32, intr: Intr) -> Result<Option<ErrorsRsp>, Lr2021Error> {
+    if !intr.cmd() {
+        return Ok(None);
+    }
+    let errors = lr2021.get_errors().await?;
+    lr2021.clear_errors().await?;
+    Ok(Some(errors))
+}
@@ -2,5 +2,20 @@
 
 pub mod ble_adv;
 pub mod board;
+pub mod bpsk_utils;
+pub mod error;
+pub mod fifo_utils;
+pub mod fsk_utils;
+pub mod intr_utils;
+pub mod lora_utils;
+pub mod lrfhss_utils;
+pub mod pkt_utils;
+pub mod radio_utils;
+pub mod reg_utils;
+pub mod ranging_utils;
+pub mod temp_utils;
+pub mod uart_command;
+pub mod wisun_utils;
+pub mod wmbus_utils;
 pub mod zwave_utils;
 pub mod zigbee_utils;
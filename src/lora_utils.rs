@@ -0,0 +1,366 @@
+use defmt::Format;
+use embassy_time::{Duration, Timer};
+use lr2021::{lora::{set_lora_address_cmd, set_lora_packet_params_cmd, set_lora_synch_timeout_cmd, set_lora_syncword_extended_cmd, set_lora_tx_sync_cmd, GetLoraPacketStatusRsp, HeaderType, TimeoutFormat, TxSyncFunction}, system::{ChipMode, DioNum}, Lr2021Error};
+
+use crate::{
+    board::Lr2021Stm32,
+    error::AppError,
+    intr_utils::{wait_irq, IntrExt, IntrFlag},
+    radio_utils::{duration_to_ticks, set_rx_for},
+};
+
+/// IQ polarity following the LoRaWAN convention
+///
+/// Uplinks (end-device to gateway) use standard IQ and downlinks (gateway to end-device) use inverted IQ,
+/// so that end-devices never receive each other uplinks. An end-device thus transmits with Uplink
+/// and receives with Downlink, and a gateway does the opposite: a mismatch is the classic
+/// "TX works but RX never receives" issue.
+/// This is independent of the syncword (0x34 on public networks, 0x12 on private networks),
+/// which separates networks while the IQ polarity separates the link directions.
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub enum LoraIqMode {
+    /// Standard IQ
+    Uplink,
+    /// Inverted IQ
+    Downlink,
+}
+
+impl LoraIqMode {
+    pub fn is_inverted(&self) -> bool {
+        *self == LoraIqMode::Downlink
+    }
+}
+
+/// LoRa packet parameters, keeping track of the payload length in implicit header mode
+///
+/// In implicit mode the header is not transmitted: payload length, CRC presence and coding rate
+/// (part of the modulation parameters) must be agreed beforehand and configured identically
+/// on both sides. The receiver then reads exactly the fixed payload length.
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub struct LoraPacketBuilder {
+    pbl_len: u16,
+    pld_len: u8,
+    implicit: bool,
+    crc_en: bool,
+    tx_iq: LoraIqMode,
+    rx_iq: LoraIqMode,
+}
+
+/// Default preamble length in symbols
+pub const LORA_PBL_LEN_DEFAULT: u16 = 8;
+
+impl LoraPacketBuilder {
+    /// Explicit header packet, with CRC, standard IQ and 8 symbols preamble
+    /// The payload length is only a maximum for the receiver
+    pub fn explicit(max_len: u8) -> Self {
+        Self {
+            pbl_len: LORA_PBL_LEN_DEFAULT,
+            pld_len: max_len,
+            implicit: false,
+            crc_en: true,
+            tx_iq: LoraIqMode::Uplink,
+            rx_iq: LoraIqMode::Uplink,
+        }
+    }
+
+    /// Implicit header packet with a fixed payload length, with CRC, standard IQ and 8 symbols preamble
+    pub fn implicit(pld_len: u8) -> Self {
+        Self {
+            implicit: true,
+            ..Self::explicit(pld_len)
+        }
+    }
+
+    /// Set the preamble length in symbols
+    pub fn with_preamble(mut self, pbl_len: u16) -> Self {
+        self.pbl_len = pbl_len;
+        self
+    }
+
+    /// Enable/Disable the payload CRC
+    pub fn with_crc(mut self, en: bool) -> Self {
+        self.crc_en = en;
+        self
+    }
+
+    /// Set the IQ polarity used in TX and in RX
+    pub fn with_iq(mut self, tx: LoraIqMode, rx: LoraIqMode) -> Self {
+        self.tx_iq = tx;
+        self.rx_iq = rx;
+        self
+    }
+
+    /// IQ polarity of a LoRaWAN end-device: transmit uplinks, receive downlinks
+    pub fn end_device(self) -> Self {
+        self.with_iq(LoraIqMode::Uplink, LoraIqMode::Downlink)
+    }
+
+    /// IQ polarity of a LoRaWAN gateway: transmit downlinks, receive uplinks
+    pub fn gateway(self) -> Self {
+        self.with_iq(LoraIqMode::Downlink, LoraIqMode::Uplink)
+    }
+
+    /// IQ polarity used for TX (is_rx false) or RX (is_rx true)
+    pub fn iq(&self, is_rx: bool) -> LoraIqMode {
+        if is_rx {self.rx_iq} else {self.tx_iq}
+    }
+
+    /// Payload length: fixed length in implicit mode, maximum length in explicit mode
+    pub fn pld_len(&self) -> u8 {
+        self.pld_len
+    }
+
+    pub fn is_implicit(&self) -> bool {
+        self.implicit
+    }
+
+    pub fn header_type(&self) -> HeaderType {
+        if self.implicit {HeaderType::Implicit} else {HeaderType::Explicit}
+    }
+}
+
+/// Configure the LoRa packet parameters for the next TX (is_rx false) or RX (is_rx true)
+/// The IQ polarity can differ between TX and RX, so this must be called before each change of direction.
+/// Packet type must be set to LoRa
+pub async fn config_lora_packet(lr2021: &mut Lr2021Stm32, params: &LoraPacketBuilder, is_rx: bool) -> Result<(), Lr2021Error> {
+    let invert_iq = params.iq(is_rx).is_inverted();
+    let req = set_lora_packet_params_cmd(params.pbl_len, params.pld_len, params.header_type(), params.crc_en, invert_iq);
+    lr2021.cmd_wr(&req).await
+}
+
+/// Length of the last packet received:
+/// the fixed payload length in implicit mode, or the length from the header in explicit mode
+pub async fn lora_rx_pkt_len(lr2021: &mut Lr2021Stm32, params: &LoraPacketBuilder) -> Result<u16, Lr2021Error> {
+    if params.implicit {
+        Ok(params.pld_len as u16)
+    } else {
+        lr2021.get_rx_pkt_len().await
+    }
+}
+
+/// LoRa syncword, selecting the network
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub enum LoraSyncword {
+    /// Public network (LoRaWAN): 0x34
+    Public,
+    /// Private network: 0x12
+    Private,
+    /// Any other syncword
+    Custom(u8),
+}
+
+impl LoraSyncword {
+    /// Syncword as a byte (legacy format used by SX127x/SX126x)
+    pub fn value(&self) -> u8 {
+        match self {
+            LoraSyncword::Public => 0x34,
+            LoraSyncword::Private => 0x12,
+            LoraSyncword::Custom(sw) => *sw,
+        }
+    }
+
+    /// Syncword in the extended format: two symbols on 5 bits each.
+    /// Each nibble of the legacy syncword is a symbol with a 16-step resolution,
+    /// so it is shifted by one bit to get the 32-step resolution of the extended format:
+    /// e.g. 0x34 gives (0x06, 0x08), the values used by LoRaWAN gateways.
+    pub fn to_extended(&self) -> (u8, u8) {
+        let sw = self.value();
+        ((sw >> 4) << 1, (sw & 0xF) << 1)
+    }
+}
+
+/// Set the LoRa syncword of the network (public, private or custom)
+/// Packet type must be set to LoRa
+pub async fn set_lora_network(lr2021: &mut Lr2021Stm32, net: LoraSyncword) -> Result<(), Lr2021Error> {
+    let (sw1, sw2) = net.to_extended();
+    lr2021.cmd_wr(&set_lora_syncword_extended_cmd(sw1, sw2)).await
+}
+
+/// Maximum mantissa of a synchronisation timeout (5 bits)
+const SYNC_TIMEOUT_MANT_MAX: u32 = 31;
+
+/// Encode a synchronisation timeout in symbols:
+///  - up to 255 symbols: number of symbols, exact value
+///  - above: mantissa on bits 7:3 and exponent on bits 2:0, i.e. mant * 2^(2*exp+1) symbols,
+///    with the smallest exponent so the timeout is rounded up by less than 2^(2*exp+1) symbols
+///
+/// Return the byte to send, its format and the actual timeout in symbols
+pub fn encode_sync_timeout(symbols: u16) -> (u8, TimeoutFormat, u32) {
+    if symbols <= u8::MAX as u16 {
+        return (symbols as u8, TimeoutFormat::NumberOfSymbols, symbols as u32);
+    }
+    let symbols = symbols as u32;
+    let mut exp = 0;
+    loop {
+        let unit = 1 << (2 * exp + 1);
+        let mant = symbols.div_ceil(unit);
+        if mant <= SYNC_TIMEOUT_MANT_MAX {
+            return (((mant << 3) | exp) as u8, TimeoutFormat::MantissaExponent, mant * unit);
+        }
+        exp += 1;
+    }
+}
+
+/// Set the number of symbols to wait for the synchronisation before a timeout in single RX
+/// (e.g. a LoRaWAN class A receive window), the encoding is selected automatically (see encode_sync_timeout).
+/// Return the actual timeout in symbols, which can be rounded up above 255 symbols
+/// Packet type must be set to LoRa
+pub async fn set_lora_sync_timeout(lr2021: &mut Lr2021Stm32, symbols: u16) -> Result<u32, Lr2021Error> {
+    let (value, format, actual) = encode_sync_timeout(symbols);
+    lr2021.cmd_wr(&set_lora_synch_timeout_cmd(value, format)).await?;
+    Ok(actual)
+}
+
+/// Role of a board in a synchronized LoRa transmission
+///
+/// Several boards share one DIO line (same DIO on all boards, connected together):
+/// the master drives the line when it starts its transmission and the slaves, armed with set_tx beforehand,
+/// wait for the edge on the line to start theirs. All packets then start within a few microseconds,
+/// e.g. for concurrent transmissions tests or to emulate a collision with a controlled time offset.
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub enum LoraTxSyncRole {
+    /// Transmission starts on set_tx as usual
+    Disabled,
+    /// Drive the synchronisation DIO at the start of the transmission
+    Master,
+    /// Wait for the synchronisation DIO to start the transmission
+    Slave,
+}
+
+/// Configure the synchronized transmission (see LoraTxSyncRole for the wiring)
+/// The DIO must not be used for anything else (IRQ, RF switch, ...).
+/// Packet type must be set to LoRa
+pub async fn configure_lora_tx_sync(lr2021: &mut Lr2021Stm32, role: LoraTxSyncRole, dio: DioNum) -> Result<(), Lr2021Error> {
+    let function = match role {
+        LoraTxSyncRole::Disabled => TxSyncFunction::None,
+        LoraTxSyncRole::Master => TxSyncFunction::Master,
+        LoraTxSyncRole::Slave => TxSyncFunction::Slave,
+    };
+    lr2021.cmd_wr(&set_lora_tx_sync_cmd(function, dio)).await
+}
+
+/// Margin added to the RX window when waiting for its end
+const SNIFF_MARGIN: Duration = Duration::from_millis(2);
+
+/// Reduced-power reception by periodic preamble sniffing, with the chip in standby between windows:
+/// listen during rx_period, go to standby RC during standby_period if nothing was detected, and repeat.
+/// This is a software duty-cycle: the MCU stays awake to time the windows and the chip only reaches
+/// the standby consumption, not the sleep one (no retention, no wake-up by the chip RTC).
+/// Return once a preamble or header is detected, leaving the chip in RX to receive the packet,
+/// or OutOfRange(0) for an rx_period rounding to 0 chip ticks (a null RX timeout means single reception without timeout).
+/// rx_period should cover at least a few preamble symbols, and the preamble of the transmitter
+/// must be longer than standby_period + rx_period to be caught.
+/// The RX timeout must stop on preamble (see radio_utils::set_rx_timeout_stop) otherwise the packet
+/// reception is cut by the end of the RX window. Pending interrupts are cleared before each window.
+pub async fn rx_standby_cycle(lr2021: &mut Lr2021Stm32, rx_period: Duration, standby_period: Duration) -> Result<(), AppError> {
+    if duration_to_ticks(rx_period, u32::MAX) == 0 {
+        return Err(AppError::OutOfRange(0));
+    }
+    let mask = IntrFlag::PreambleDetected | IntrFlag::HeaderValid | IntrFlag::Timeout;
+    loop {
+        lr2021.get_and_clear_irq().await?;
+        set_rx_for(lr2021, rx_period).await?;
+        let intr = wait_irq(lr2021, mask, rx_period + SNIFF_MARGIN).await?;
+        if intr.contains(IntrFlag::PreambleDetected) || intr.contains(IntrFlag::HeaderValid) {
+            return Ok(());
+        }
+        lr2021.set_chip_mode(ChipMode::StandbyRc).await?;
+        Timer::after(standby_period).await;
+    }
+}
+
+/// LoRa detector which received a packet
+/// The main detector uses the SF of the modulation parameters, the side detectors the SF
+/// configured with the side-detector command: on a multi-SF receiver this gives the SF to use for the reply.
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub enum LoraDetector {
+    Main = 0x01,
+    Side1 = 0x02,
+    Side2 = 0x04,
+    Side3 = 0x08,
+}
+
+/// All detectors, in the bit order of the detector mask
+const LORA_DETECTORS: [LoraDetector; 4] = [LoraDetector::Main, LoraDetector::Side1, LoraDetector::Side2, LoraDetector::Side3];
+
+impl LoraDetector {
+    /// Detector corresponding to the lowest bit set in a detector mask (None if the mask is empty)
+    pub fn from_mask(mask: u8) -> Option<Self> {
+        Self::iter_mask(mask).next()
+    }
+
+    /// Iterate over all the detectors set in a mask (in CAD mode several detectors can trigger)
+    pub fn iter_mask(mask: u8) -> impl Iterator<Item = LoraDetector> {
+        LORA_DETECTORS.into_iter().filter(move |d| mask & (*d as u8) != 0)
+    }
+}
+
+/// Decoded detector information from the LoRa packet status
+pub trait LoraDetectorStatus {
+    /// Detector which received the last packet
+    fn detector_enum(&self) -> Option<LoraDetector>;
+    /// All detectors reported in the last status
+    fn detectors(&self) -> impl Iterator<Item = LoraDetector>;
+}
+
+impl LoraDetectorStatus for GetLoraPacketStatusRsp {
+    fn detector_enum(&self) -> Option<LoraDetector> {
+        LoraDetector::from_mask(self.detector())
+    }
+
+    fn detectors(&self) -> impl Iterator<Item = LoraDetector> {
+        LoraDetector::iter_mask(self.detector())
+    }
+}
+
+/// Maximum number of address bytes compared by the LoRa address filter
+pub const LORA_ADDR_LEN_MAX: u8 = 8;
+
+/// LoRa address filtering: length bytes of the payload starting at position (0 is the first payload byte)
+/// are compared with the length least significant bytes of address.
+/// Packets not matching are dropped by the chip without waking up the host.
+/// A length of 0 disables the filtering.
+#[derive(Debug, Clone, Copy, Format, PartialEq, Default)]
+pub struct LoraAddrFilter {
+    pub position: u8,
+    pub length: u8,
+    pub address: u64,
+}
+
+impl LoraAddrFilter {
+    /// Filtering disabled: all packets are received
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Compare the first length bytes of the payload with address
+    pub fn new(address: u64, length: u8) -> Self {
+        Self {position: 0, length, address}
+    }
+
+    /// Set the position of the address in the payload
+    pub fn with_position(mut self, position: u8) -> Self {
+        self.position = position;
+        self
+    }
+
+    /// Position of the first byte after the address in the payload
+    pub fn end(&self) -> usize {
+        self.position as usize + self.length as usize
+    }
+}
+
+/// Configure the LoRa address filtering
+/// The filter is checked against the packet parameters: OutOfRange (with the length) if more than 8 bytes
+/// are compared, PayloadTooLong (with the end of the address) if the address does not fit in the payload.
+pub async fn set_lora_address_filter(lr2021: &mut Lr2021Stm32, filter: &LoraAddrFilter, params: &LoraPacketBuilder) -> Result<(), AppError> {
+    if filter.length > LORA_ADDR_LEN_MAX {
+        return Err(AppError::OutOfRange(filter.length as u32));
+    }
+    if filter.length > 0 && filter.end() > params.pld_len() as usize {
+        return Err(AppError::PayloadTooLong(filter.end()));
+    }
+    let req = set_lora_address_cmd(filter.length, filter.position, filter.address);
+    lr2021.cmd_wr(&req).await?;
+    Ok(())
+}
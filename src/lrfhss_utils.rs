@@ -0,0 +1,127 @@
+use defmt::Format;
+use lr2021::{lrfhss::{lr_fhss_build_frame_cmd, set_lr_fhss_sync_word_cmd}, radio::PacketType, Lr2021Error};
+
+use crate::{board::Lr2021Stm32, error::AppError};
+
+/// Default LR-FHSS syncword (LoRaWAN)
+pub const LRFHSS_SYNCWORD: u32 = 0x2C0F7995;
+
+/// LR-FHSS coding rate
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub enum LrFhssCr {
+    Cr5p6 = 0,
+    Cr2p3 = 1,
+    Cr1p2 = 2,
+    Cr1p3 = 3,
+}
+
+/// LR-FHSS grid: minimum spacing between two hops
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub enum LrFhssGrid {
+    /// 25.39kHz grid (FCC)
+    Grid25k = 0,
+    /// 3.9kHz grid (ETSI)
+    Grid3k9 = 1,
+}
+
+/// LR-FHSS occupied bandwidth
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub enum LrFhssBw {
+    Bw39k = 0,
+    Bw85k = 1,
+    Bw136k = 2,
+    Bw183k = 3,
+    Bw335k = 4,
+    Bw386k = 5,
+    Bw722k = 6,
+    Bw773k = 7,
+    Bw1523k = 8,
+    Bw1574k = 9,
+}
+
+/// LR-FHSS frame parameters (see set_lrfhss_packet)
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub struct LrFhssParams {
+    /// Grid of the hopping frequencies
+    pub grid: LrFhssGrid,
+    /// Bandwidth covered by the hops
+    pub bw: LrFhssBw,
+    /// Coding rate of the payload
+    pub cr: LrFhssCr,
+    /// Number of header replicas (1 to 4)
+    pub nb_hdr: u8,
+    /// Hopping sequence index: pick a different one per device to spread the collisions
+    pub sequence: u16,
+    /// Disable to transmit all fragments on the RF frequency (test only)
+    pub hopping: bool,
+    /// Offset of the hopping grid in grid steps
+    pub device_offset: i8,
+}
+
+/// EU configuration: 3.9kHz grid, 136kHz bandwidth, CR 1/3, 3 headers (LoRaWAN DR8)
+impl Default for LrFhssParams {
+    fn default() -> Self {
+        Self {
+            grid: LrFhssGrid::Grid3k9,
+            bw: LrFhssBw::Bw136k,
+            cr: LrFhssCr::Cr1p3,
+            nb_hdr: 3,
+            sequence: 0,
+            hopping: true,
+            device_offset: 0,
+        }
+    }
+}
+
+impl LrFhssParams {
+    /// Set the hopping sequence index
+    pub fn with_sequence(mut self, sequence: u16) -> Self {
+        self.sequence = sequence;
+        self
+    }
+}
+
+/// Select the LR-FHSS packet type and set the syncword
+/// The modulation itself (GMSK 488b/s) is fixed: only the frame parameters are configurable (see set_lrfhss_packet)
+pub async fn set_lrfhss_modulation(lr2021: &mut Lr2021Stm32, syncword: u32) -> Result<(), Lr2021Error> {
+    lr2021.set_packet_type(PacketType::LrFhss).await?;
+    lr2021.cmd_wr(&set_lr_fhss_sync_word_cmd(syncword)).await
+}
+
+/// Build the LR-FHSS frame for a payload of pld_len bytes already in the TX FIFO:
+/// the chip encodes the payload and computes the hop table from the parameters.
+/// Return OutOfRange with the value for a number of headers outside 1 to 4
+pub async fn set_lrfhss_packet(lr2021: &mut Lr2021Stm32, params: &LrFhssParams, pld_len: u8) -> Result<(), AppError> {
+    if !(1..=4).contains(&params.nb_hdr) {
+        return Err(AppError::OutOfRange(params.nb_hdr as u32));
+    }
+    let req = lr_fhss_build_frame_cmd(
+        params.nb_hdr,
+        params.cr as u8,
+        params.grid as u8,
+        params.hopping,
+        params.bw as u8,
+        params.sequence,
+        params.device_offset,
+        pld_len,
+    );
+    lr2021.cmd_wr(&req).await?;
+    Ok(())
+}
+
+/// Send a payload with LR-FHSS (see set_lrfhss_modulation for the packet type)
+/// The payload is written in the TX FIFO, the frame built and the transmission started.
+/// The FHSS interrupt is raised on each hop and TxDone at the end of the frame:
+/// map IntrFlag::Fhss on a DIO to be notified of the hops.
+pub async fn lrfhss_tx(lr2021: &mut Lr2021Stm32, params: &LrFhssParams, payload: &[u8]) -> Result<(), AppError> {
+    let len = payload.len();
+    let buffer = lr2021.buffer_mut();
+    if len > buffer.len() || len > u8::MAX as usize {
+        return Err(AppError::PayloadTooLong(len));
+    }
+    buffer[..len].copy_from_slice(payload);
+    lr2021.wr_tx_fifo(len).await?;
+    set_lrfhss_packet(lr2021, params, len as u8).await?;
+    lr2021.set_tx(0).await?;
+    Ok(())
+}
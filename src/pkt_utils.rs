@@ -0,0 +1,377 @@
+use defmt::{info, write, Format, Formatter};
+use embassy_time::Duration;
+use lr2021::{
+    ble::{BlePacketStatusRsp, BleRxStatsRsp},
+    flrc::{FlrcPacketStatusRsp, FlrcRxStatsRsp},
+    fsk::{GetFskPacketStatusRsp, GetFskRxStatsRsp},
+    lora::{GetLoraPacketStatusRsp, GetLoraRxStatsRsp},
+    ook::{OokPacketStatusRsp, OokRxStatsRsp},
+    radio::PacketType,
+    status::Intr,
+    zigbee::{ZigbeePacketStatusRsp, ZigbeeRxStatsRsp},
+    zwave::{ZwavePacketStatusRsp, ZwaveRxStatsRsp},
+    Lr2021Error,
+};
+
+use crate::{
+    board::Lr2021Stm32,
+    error::AppError,
+    intr_utils::{wait_irq, IntrExt, IntrFlag},
+    radio_utils::{get_packet_type, set_rx_for},
+};
+
+/// Length of the packet received, None when no packet is available
+/// The length is cross-checked with the RX FIFO level: an empty FIFO means the length
+/// (and packet status) are stale, e.g. when the FIFO was already read or cleared.
+pub async fn rx_packet_len(lr2021: &mut Lr2021Stm32) -> Result<Option<u16>, Lr2021Error> {
+    let len = lr2021.get_rx_pkt_len().await?;
+    if len == 0 {
+        return Ok(None);
+    }
+    let lvl = lr2021.get_rx_fifo_lvl().await?;
+    Ok(if lvl == 0 {None} else {Some(len)})
+}
+
+/// Common information available in the packet status of all packet types
+pub trait PacketStatus {
+    /// Length of the last packet received
+    fn pkt_len(&self) -> u16;
+    /// Average RSSI of the last packet received in dBm
+    fn rssi_avg_dbm(&self) -> i16;
+    /// Link quality indicator (unit is 0.25dB) if available
+    fn lqi(&self) -> Option<u8>;
+}
+
+/// Convert an RSSI from the chip (unit is -0.5dBm) to dBm
+fn rssi_to_dbm(rssi: u16) -> i16 {
+    -((rssi >> 1) as i16)
+}
+
+impl PacketStatus for GetFskPacketStatusRsp {
+    fn pkt_len(&self) -> u16 {
+        self.pkt_len() as u16
+    }
+    fn rssi_avg_dbm(&self) -> i16 {
+        rssi_to_dbm(self.rssi_avg() as u16)
+    }
+    fn lqi(&self) -> Option<u8> {
+        Some(self.lqi() as u8)
+    }
+}
+
+impl PacketStatus for GetLoraPacketStatusRsp {
+    fn pkt_len(&self) -> u16 {
+        self.pkt_len() as u16
+    }
+    fn rssi_avg_dbm(&self) -> i16 {
+        rssi_to_dbm(self.rssi_pkt() as u16)
+    }
+    fn lqi(&self) -> Option<u8> {
+        None
+    }
+}
+
+impl PacketStatus for ZwavePacketStatusRsp {
+    fn pkt_len(&self) -> u16 {
+        self.pkt_len() as u16
+    }
+    fn rssi_avg_dbm(&self) -> i16 {
+        rssi_to_dbm(self.rssi_avg() as u16)
+    }
+    fn lqi(&self) -> Option<u8> {
+        Some(self.lqi() as u8)
+    }
+}
+
+impl PacketStatus for OokPacketStatusRsp {
+    fn pkt_len(&self) -> u16 {
+        self.pkt_len() as u16
+    }
+    fn rssi_avg_dbm(&self) -> i16 {
+        rssi_to_dbm(self.rssi_avg() as u16)
+    }
+    fn lqi(&self) -> Option<u8> {
+        Some(self.lqi() as u8)
+    }
+}
+
+impl PacketStatus for BlePacketStatusRsp {
+    fn pkt_len(&self) -> u16 {
+        self.pkt_len() as u16
+    }
+    fn rssi_avg_dbm(&self) -> i16 {
+        rssi_to_dbm(self.rssi_avg() as u16)
+    }
+    fn lqi(&self) -> Option<u8> {
+        None
+    }
+}
+
+impl PacketStatus for FlrcPacketStatusRsp {
+    fn pkt_len(&self) -> u16 {
+        self.pkt_len() as u16
+    }
+    fn rssi_avg_dbm(&self) -> i16 {
+        rssi_to_dbm(self.rssi_avg() as u16)
+    }
+    fn lqi(&self) -> Option<u8> {
+        None
+    }
+}
+
+impl PacketStatus for ZigbeePacketStatusRsp {
+    fn pkt_len(&self) -> u16 {
+        self.pkt_len() as u16
+    }
+    fn rssi_avg_dbm(&self) -> i16 {
+        rssi_to_dbm(self.rssi_avg() as u16)
+    }
+    fn lqi(&self) -> Option<u8> {
+        Some(self.lqi() as u8)
+    }
+}
+
+/// Result of the address filtering of the last packet received (AddrComp::Node or AddrComp::NodeBcast)
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub enum AddrMatch {
+    /// No address match (or address filtering disabled)
+    None,
+    /// Packet addressed to the node address
+    Node,
+    /// Packet addressed to the broadcast address
+    Broadcast,
+}
+
+impl AddrMatch {
+    /// Convert the two match flags of a packet status: both cannot be set at the same time
+    /// since the node and broadcast addresses must differ, broadcast takes precedence if it happens.
+    pub fn from_flags(node: bool, bcast: bool) -> Self {
+        match (node, bcast) {
+            (_, true) => AddrMatch::Broadcast,
+            (true, false) => AddrMatch::Node,
+            (false, false) => AddrMatch::None,
+        }
+    }
+}
+
+/// Packet status with address filtering information
+pub trait AddrMatchStatus {
+    /// Which address matched in the last packet received
+    fn addr_match(&self) -> AddrMatch;
+}
+
+impl AddrMatchStatus for GetFskPacketStatusRsp {
+    fn addr_match(&self) -> AddrMatch {
+        AddrMatch::from_flags(self.addr_match_node(), self.addr_match_bcast())
+    }
+}
+
+impl AddrMatchStatus for OokPacketStatusRsp {
+    fn addr_match(&self) -> AddrMatch {
+        AddrMatch::from_flags(self.addr_match_node(), self.addr_match_bcast())
+    }
+}
+
+/// Information on the last packet received, common to all packet types
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub struct CommonRxInfo {
+    /// Average RSSI in dBm
+    pub rssi_avg_dbm: i16,
+    /// Link quality indicator (unit is 0.25dB) if available
+    pub lqi: Option<u8>,
+    /// SNR (unit is 0.25dB), LoRa only
+    pub snr: Option<i8>,
+    pub crc_ok: bool,
+}
+
+impl CommonRxInfo {
+    fn from_status<S: PacketStatus>(status: &S, crc_ok: bool) -> Self {
+        Self {
+            rssi_avg_dbm: PacketStatus::rssi_avg_dbm(status),
+            lqi: PacketStatus::lqi(status),
+            snr: None,
+            crc_ok,
+        }
+    }
+}
+
+/// Read the packet status matching the current packet type
+async fn get_rx_info(lr2021: &mut Lr2021Stm32, crc_ok: bool) -> Result<CommonRxInfo, AppError> {
+    let info = match get_packet_type(lr2021).await? {
+        Some(PacketType::Lora) => {
+            let status = lr2021.get_lora_packet_status().await?;
+            CommonRxInfo {
+                snr: Some(status.snr_pkt() as i8),
+                ..CommonRxInfo::from_status(&status, crc_ok)
+            }
+        }
+        Some(PacketType::FskGeneric) |
+        Some(PacketType::FskLegacy) |
+        Some(PacketType::Wmbus) |
+        Some(PacketType::Wisun) => CommonRxInfo::from_status(&lr2021.get_fsk_packet_status().await?, crc_ok),
+        Some(PacketType::Ble) => CommonRxInfo::from_status(&lr2021.get_ble_packet_status().await?, crc_ok),
+        Some(PacketType::Ook) => CommonRxInfo::from_status(&lr2021.get_ook_packet_status().await?, crc_ok),
+        Some(PacketType::Zwave) => CommonRxInfo::from_status(&lr2021.get_zwave_packet_status().await?, crc_ok),
+        Some(PacketType::Flrc) => CommonRxInfo::from_status(&lr2021.get_flrc_packet_status().await?, crc_ok),
+        Some(PacketType::Zigbee) => CommonRxInfo::from_status(&lr2021.get_zigbee_packet_status().await?, crc_ok),
+        Some(p) => return Err(AppError::UnsupportedPacketType(p as u8)),
+        None => return Err(AppError::UnsupportedPacketType(0xFF)),
+    };
+    Ok(info)
+}
+
+/// Read the last packet received into dst, with its status
+/// The status command is selected from the packet type currently configured in the chip.
+/// intr are the interrupts read by the caller when handling the RX done, used for crc_ok.
+/// Return the number of bytes read (0 if no packet is available)
+pub async fn receive_packet(lr2021: &mut Lr2021Stm32, intr: Intr, dst: &mut [u8]) -> Result<(usize, CommonRxInfo), AppError> {
+    let info = get_rx_info(lr2021, !intr.crc_error()).await?;
+    let len = rx_packet_len(lr2021).await?.unwrap_or(0) as usize;
+    if len > dst.len() {
+        lr2021.rd_rx_fifo_to(dst).await?;
+        return Err(AppError::BufferTooSmall(dst.len()));
+    }
+    lr2021.rd_rx_fifo_to(&mut dst[..len]).await?;
+    Ok((len, info))
+}
+
+/// Maximum duration of the transmission in tx_then_rx (long enough for a LoRa SF12 packet)
+const TX_DONE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Margin added to the RX timeout when waiting for the end of the reply window
+const RX_DONE_MARGIN: Duration = Duration::from_millis(2);
+
+/// Send a packet then listen for a reply during reply_timeout: request/response primitive for ack-based protocols.
+/// Packet type and modulation must be configured, with the fallback mode set to FS for a shorter turnaround.
+/// Interrupts are polled (see wait_irq) and cleared; the turnaround is a few hundreds of microseconds,
+/// set_auto_txrx should be used instead when the reply comes faster.
+/// Return the length of the reply written in reply, None if the transmission or the reply timed out,
+/// or CrcError if the reply was received with a wrong CRC
+pub async fn tx_then_rx(lr2021: &mut Lr2021Stm32, payload: &[u8], reply: &mut [u8], reply_timeout: Duration) -> Result<Option<usize>, AppError> {
+    let len = payload.len();
+    let buffer = lr2021.buffer_mut();
+    if len > buffer.len() {
+        return Err(AppError::PayloadTooLong(len));
+    }
+    buffer[..len].copy_from_slice(payload);
+    lr2021.get_and_clear_irq().await?;
+    lr2021.wr_tx_fifo(len).await?;
+    lr2021.set_tx(0).await?;
+    let intr = wait_irq(lr2021, IntrFlag::TxDone.into(), TX_DONE_TIMEOUT).await?;
+    if !intr.contains(IntrFlag::TxDone) {
+        return Ok(None);
+    }
+    set_rx_for(lr2021, reply_timeout).await?;
+    let mask = IntrFlag::RxDone | IntrFlag::CrcError | IntrFlag::Timeout;
+    let intr = wait_irq(lr2021, mask, reply_timeout + RX_DONE_MARGIN).await?;
+    if !intr.contains(IntrFlag::RxDone) {
+        return Ok(None);
+    }
+    if intr.contains(IntrFlag::CrcError) {
+        lr2021.clear_rx_fifo().await?;
+        return Err(AppError::CrcError(0));
+    }
+    let (n, _) = receive_packet(lr2021, intr, reply).await?;
+    Ok(Some(n))
+}
+
+/// Counters common to the RX statistics of all packet types
+/// Counters not available for a packet type return None
+#[allow(async_fn_in_trait)]
+pub trait RxStats: Sized {
+    /// Read the statistics from the chip
+    async fn read(lr2021: &mut Lr2021Stm32) -> Result<Self, Lr2021Error>;
+    /// Number of packets received
+    fn packets(&self) -> u16;
+    /// Number of packets received with a CRC error
+    fn crc_errors(&self) -> u16;
+    /// Number of packets received with a length error
+    fn len_errors(&self) -> Option<u16> {None}
+    /// Number of header errors
+    fn header_errors(&self) -> Option<u16> {None}
+    /// Number of preambles detected
+    fn preamble_detections(&self) -> Option<u16> {None}
+    /// Number of syncword failures (false sync for LoRa)
+    fn sync_failures(&self) -> Option<u16> {None}
+    /// Number of RX timeouts
+    fn timeouts(&self) -> Option<u16> {None}
+}
+
+impl RxStats for GetFskRxStatsRsp {
+    async fn read(lr2021: &mut Lr2021Stm32) -> Result<Self, Lr2021Error> {lr2021.get_fsk_rx_stats().await}
+    fn packets(&self) -> u16 {self.pkt_rx() as u16}
+    fn crc_errors(&self) -> u16 {self.crc_error() as u16}
+    fn len_errors(&self) -> Option<u16> {Some(self.len_error() as u16)}
+    fn preamble_detections(&self) -> Option<u16> {Some(self.pbl_det() as u16)}
+    fn sync_failures(&self) -> Option<u16> {Some(self.sync_fail() as u16)}
+}
+
+impl RxStats for GetLoraRxStatsRsp {
+    async fn read(lr2021: &mut Lr2021Stm32) -> Result<Self, Lr2021Error> {lr2021.get_lora_rx_stats().await}
+    fn packets(&self) -> u16 {self.pkt_rx() as u16}
+    fn crc_errors(&self) -> u16 {self.crc_error() as u16}
+    fn header_errors(&self) -> Option<u16> {Some(self.header_error() as u16)}
+    fn sync_failures(&self) -> Option<u16> {Some(self.false_sync() as u16)}
+}
+
+impl RxStats for FlrcRxStatsRsp {
+    async fn read(lr2021: &mut Lr2021Stm32) -> Result<Self, Lr2021Error> {lr2021.get_flrc_rx_stats().await}
+    fn packets(&self) -> u16 {self.pkt_rx() as u16}
+    fn crc_errors(&self) -> u16 {self.crc_error() as u16}
+    fn len_errors(&self) -> Option<u16> {Some(self.len_error() as u16)}
+}
+
+impl RxStats for OokRxStatsRsp {
+    async fn read(lr2021: &mut Lr2021Stm32) -> Result<Self, Lr2021Error> {lr2021.get_ook_rx_stats().await}
+    fn packets(&self) -> u16 {self.pkt_rx() as u16}
+    fn crc_errors(&self) -> u16 {self.crc_error() as u16}
+}
+
+impl RxStats for BleRxStatsRsp {
+    async fn read(lr2021: &mut Lr2021Stm32) -> Result<Self, Lr2021Error> {lr2021.get_ble_rx_stats().await}
+    fn packets(&self) -> u16 {self.pkt_rx() as u16}
+    fn crc_errors(&self) -> u16 {self.crc_error() as u16}
+    fn len_errors(&self) -> Option<u16> {Some(self.len_error() as u16)}
+}
+
+impl RxStats for ZwaveRxStatsRsp {
+    async fn read(lr2021: &mut Lr2021Stm32) -> Result<Self, Lr2021Error> {lr2021.get_zwave_rx_stats().await}
+    fn packets(&self) -> u16 {self.pkt_rx() as u16}
+    fn crc_errors(&self) -> u16 {self.crc_error() as u16}
+    fn len_errors(&self) -> Option<u16> {Some(self.len_error() as u16)}
+}
+
+impl RxStats for ZigbeeRxStatsRsp {
+    async fn read(lr2021: &mut Lr2021Stm32) -> Result<Self, Lr2021Error> {lr2021.get_zigbee_rx_stats().await}
+    fn packets(&self) -> u16 {self.pkt_rx() as u16}
+    fn crc_errors(&self) -> u16 {self.crc_error() as u16}
+    fn len_errors(&self) -> Option<u16> {Some(self.len_error() as u16)}
+}
+
+/// Read the RX statistics and reset them
+/// Both commands are sent back-to-back, but a packet ending between the two is still lost from the statistics:
+/// the chip updates the counters at the end of each packet and the reset clears all of them.
+/// The race window is only a few tens of us, negligible compared to the packet duration for most use-cases.
+pub async fn take_rx_stats<S: RxStats>(lr2021: &mut Lr2021Stm32) -> Result<S, Lr2021Error> {
+    let stats = S::read(lr2021).await?;
+    lr2021.clear_rx_stats().await?;
+    Ok(stats)
+}
+
+/// Display only the counters available
+struct RxStatsFmt<'a, S: RxStats>(&'a S);
+
+impl<S: RxStats> Format for RxStatsFmt<'_, S> {
+    fn format(&self, fmt: Formatter) {
+        write!(fmt, "RX={}, CRC Err={}", self.0.packets(), self.0.crc_errors());
+        if let Some(n) = self.0.len_errors() {write!(fmt, ", LenErr={}", n);}
+        if let Some(n) = self.0.header_errors() {write!(fmt, ", HdrErr={}", n);}
+        if let Some(n) = self.0.preamble_detections() {write!(fmt, ", Detect={}", n);}
+        if let Some(n) = self.0.sync_failures() {write!(fmt, ", SyncFail={}", n);}
+        if let Some(n) = self.0.timeouts() {write!(fmt, ", Timeout={}", n);}
+    }
+}
+
+/// Log the RX statistics on the debug link
+pub fn log_rx_stats(stats: &impl RxStats) {
+    info!("[RX] Stats | {}", RxStatsFmt(stats));
+}
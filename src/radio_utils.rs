@@ -0,0 +1,759 @@
+use core::{cell::Cell, ops::BitOr};
+
+use defmt::Format;
+use embassy_stm32::gpio::Output;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use lr2021::{
+    lora::{LoraBw, Sf},
+    radio::{get_packet_type_req, get_rssi_inst_req, set_auto_rx_tx_cmd, set_cad_cmd, set_cad_params_cmd, set_stop_timeout_cmd, set_tx_test_mode_cmd, AutoTxrxMode, ExitMode, FallbackMode, PacketType, PacketTypeRsp, RampTime, RssiInstRsp, RxBoost, RxPath, StopTimeout, TestMode, TimestampIndex, TimestampSource},
+    status::ResetSrc,
+    system::{calibrate_cmd, set_dio_rf_switch_config_cmd, set_eol_config_cmd, ChipMode, DioFunc, DioNum, PullDrive, Trim, VersionRsp},
+    Lr2021Error, RxBw
+};
+
+use crate::{board::Lr2021Stm32, error::AppError, intr_utils::IntrFlag};
+
+/// Table of all RX bandwidth with their nominal value in Hz, sorted by increasing bandwidth
+const RX_BW_TABLE : [(u32, RxBw); 80] = [
+    (    3_500, RxBw::Bw3p5),
+    (    4_200, RxBw::Bw4p2),
+    (    5_200, RxBw::Bw5p2),
+    (    6_000, RxBw::Bw6),
+    (    7_400, RxBw::Bw7p4),
+    (    8_000, RxBw::Bw8),
+    (    9_600, RxBw::Bw9p6),
+    (   10_000, RxBw::Bw10),
+    (   11_000, RxBw::Bw11),
+    (   12_000, RxBw::Bw12),
+    (   13_000, RxBw::Bw13),
+    (   14_000, RxBw::Bw14),
+    (   16_000, RxBw::Bw16),
+    (   17_000, RxBw::Bw17),
+    (   19_000, RxBw::Bw19),
+    (   20_000, RxBw::Bw20),
+    (   22_000, RxBw::Bw22),
+    (   23_000, RxBw::Bw23),
+    (   24_000, RxBw::Bw24),
+    (   27_000, RxBw::Bw27),
+    (   29_000, RxBw::Bw29),
+    (   32_000, RxBw::Bw32),
+    (   33_000, RxBw::Bw33),
+    (   34_000, RxBw::Bw34),
+    (   35_000, RxBw::Bw35),
+    (   38_000, RxBw::Bw38),
+    (   41_000, RxBw::Bw41),
+    (   44_000, RxBw::Bw44),
+    (   46_000, RxBw::Bw46),
+    (   48_000, RxBw::Bw48),
+    (   55_000, RxBw::Bw55),
+    (   59_000, RxBw::Bw59),
+    (   64_000, RxBw::Bw64),
+    (   66_000, RxBw::Bw66),
+    (   69_000, RxBw::Bw69),
+    (   71_000, RxBw::Bw71),
+    (   76_000, RxBw::Bw76),
+    (   83_000, RxBw::Bw83),
+    (   89_000, RxBw::Bw89),
+    (   92_000, RxBw::Bw92),
+    (   96_000, RxBw::Bw96),
+    (  111_000, RxBw::Bw111),
+    (  119_000, RxBw::Bw119),
+    (  128_000, RxBw::Bw128),
+    (  133_000, RxBw::Bw133),
+    (  138_000, RxBw::Bw138),
+    (  142_000, RxBw::Bw142),
+    (  153_000, RxBw::Bw153),
+    (  166_000, RxBw::Bw166),
+    (  178_000, RxBw::Bw178),
+    (  185_000, RxBw::Bw185),
+    (  192_000, RxBw::Bw192),
+    (  222_000, RxBw::Bw222),
+    (  238_000, RxBw::Bw238),
+    (  256_000, RxBw::Bw256),
+    (  266_000, RxBw::Bw266),
+    (  277_000, RxBw::Bw277),
+    (  285_000, RxBw::Bw285),
+    (  307_000, RxBw::Bw307),
+    (  333_000, RxBw::Bw333),
+    (  357_000, RxBw::Bw357),
+    (  370_000, RxBw::Bw370),
+    (  384_000, RxBw::Bw384),
+    (  444_000, RxBw::Bw444),
+    (  476_000, RxBw::Bw476),
+    (  512_000, RxBw::Bw512),
+    (  533_000, RxBw::Bw533),
+    (  555_000, RxBw::Bw555),
+    (  571_000, RxBw::Bw571),
+    (  615_000, RxBw::Bw615),
+    (  666_000, RxBw::Bw666),
+    (  714_000, RxBw::Bw714),
+    (  740_000, RxBw::Bw740),
+    (  769_000, RxBw::Bw769),
+    (  888_000, RxBw::Bw888),
+    (1_111_000, RxBw::Bw1111),
+    (1_333_000, RxBw::Bw1333),
+    (2_222_000, RxBw::Bw2222),
+    (2_666_000, RxBw::Bw2666),
+    (2_857_000, RxBw::Bw2857),
+];
+
+/// Largest RX bandwidth available
+const RX_BW_MAX : (u32, RxBw) = (3_076_000, RxBw::Bw3076);
+
+/// Return the smallest RX bandwidth greater or equal to the requested bandwidth in Hz
+/// Saturate to the largest bandwidth (~3MHz) when the request is too high
+pub fn rx_bw_from_hz(hz: u32) -> RxBw {
+    RX_BW_TABLE.iter()
+        .find(|(bw_hz,_)| *bw_hz >= hz)
+        .map(|&(_,bw)| bw)
+        .unwrap_or(RX_BW_MAX.1)
+}
+
+/// Return the smallest RX bandwidth greater or equal to the requested bandwidth in kHz
+pub fn rx_bw_from_khz(khz: u32) -> RxBw {
+    rx_bw_from_hz(khz.saturating_mul(1000))
+}
+
+/// Return the nominal value in Hz of an RX bandwidth
+/// Return 0 for the automatic bandwidth
+pub fn rx_bw_to_hz(bw: RxBw) -> u32 {
+    if bw as u8 == RX_BW_MAX.1 as u8 {
+        return RX_BW_MAX.0;
+    }
+    RX_BW_TABLE.iter()
+        .find(|(_,b)| *b as u8 == bw as u8)
+        .map(|&(hz,_)| hz)
+        .unwrap_or(0)
+}
+
+/// Return the LoRa bandwidth in Hz
+pub fn lora_bw_to_hz(bw: LoraBw) -> u32 {
+    match bw {
+        LoraBw::Bw7    =>     7_812,
+        LoraBw::Bw10   =>    10_417,
+        LoraBw::Bw15   =>    15_625,
+        LoraBw::Bw20   =>    20_833,
+        LoraBw::Bw31   =>    31_250,
+        LoraBw::Bw41   =>    41_667,
+        LoraBw::Bw62   =>    62_500,
+        LoraBw::Bw101  =>   101_562,
+        LoraBw::Bw125  =>   125_000,
+        LoraBw::Bw203  =>   203_125,
+        LoraBw::Bw250  =>   250_000,
+        LoraBw::Bw406  =>   406_250,
+        LoraBw::Bw500  =>   500_000,
+        LoraBw::Bw812  =>   812_500,
+        LoraBw::Bw1000 => 1_000_000,
+    }
+}
+
+/// Return the spreading factor as a number (5 to 12)
+pub fn sf_to_u8(sf: Sf) -> u8 {
+    match sf {
+        Sf::Sf5  =>  5,
+        Sf::Sf6  =>  6,
+        Sf::Sf7  =>  7,
+        Sf::Sf8  =>  8,
+        Sf::Sf9  =>  9,
+        Sf::Sf10 => 10,
+        Sf::Sf11 => 11,
+        Sf::Sf12 => 12,
+    }
+}
+
+/// Chip timer frequency used for timeout and delay (32.768kHz, i.e. ~30.52us per tick)
+pub const TIMER_FREQ_HZ: u64 = 32_768;
+
+/// Convert a duration into chip timer ticks (1/32.768kHz), rounding to the nearest tick
+/// Saturate to max when the duration does not fit
+pub fn duration_to_ticks(d: Duration, max: u32) -> u32 {
+    let ticks = d.as_micros().saturating_mul(TIMER_FREQ_HZ).saturating_add(500_000) / 1_000_000;
+    ticks.min(max as u64) as u32
+}
+
+/// Frequency of the timestamp counter (HF clock)
+pub const HF_FREQ_HZ: u64 = 32_000_000;
+
+/// Convert timestamp ticks (1/32MHz = 31.25ns) into nanoseconds
+pub fn hf_ticks_to_ns(ticks: u32) -> u64 {
+    (ticks as u64 * 125) >> 2
+}
+
+/// Convert nanoseconds into timestamp ticks (1/32MHz = 31.25ns), rounding down
+/// Saturate to u32::MAX (~134s)
+pub fn ns_to_hf_ticks(ns: u64) -> u32 {
+    ((ns << 2) / 125).min(u32::MAX as u64) as u32
+}
+
+/// Time elapsed since the event captured by a timestamp slot (raw ticks available with lr2021.get_timestamp)
+/// The chip has three slots, TimestampIndex Ts0 to Ts2, each with its own source (see configure_timestamps).
+/// The HF counter does not run in sleep: the value is wrong if the event occurred before the chip went to sleep,
+/// and the chip gives no indication of it, so the slot must be re-armed after each wake-up.
+pub async fn timestamp_since(lr2021: &mut Lr2021Stm32, idx: TimestampIndex) -> Result<Duration, Lr2021Error> {
+    let ticks = lr2021.get_timestamp(idx).await?;
+    Ok(Duration::from_nanos(hf_ticks_to_ns(ticks)))
+}
+
+/// Number of timestamp slots available
+pub const TIMESTAMP_SLOTS: usize = 3;
+
+/// Configure the source of several timestamp slots at once,
+/// e.g. Ts0 on sync and Ts1 on RX done to measure the sync-to-rxdone latency.
+/// Up to three entries are allowed, each with a different index:
+/// return InvalidConfig with the position of the first invalid entry without configuring anything.
+pub async fn configure_timestamps(lr2021: &mut Lr2021Stm32, sources: &[(TimestampIndex, TimestampSource)]) -> Result<(), AppError> {
+    if sources.len() > TIMESTAMP_SLOTS {
+        return Err(AppError::InvalidConfig(TIMESTAMP_SLOTS));
+    }
+    for (i, (idx, _)) in sources.iter().enumerate() {
+        if sources[..i].iter().any(|(prev, _)| *prev as u8 == *idx as u8) {
+            return Err(AppError::InvalidConfig(i));
+        }
+    }
+    for &(idx, src) in sources {
+        lr2021.set_timestamp_source(idx, src).await?;
+    }
+    Ok(())
+}
+
+/// Maximum timeout for RX/TX and auto TX/RX (24 bits, ~512s)
+const TIMEOUT_MAX: u32 = 0xFF_FFFF;
+/// Timeout value for continuous RX
+const RX_CONTINUOUS: u32 = 0xFFFF_FFFF;
+
+/// Enable automatic transition to TX/RX after the end of a RX/TX
+/// Timeout applies to the automatic RX, and delay is the time between the end of a packet and the start of the next one
+/// Both are converted in ticks of 1/32.768kHz (~30.52us) and saturate (~512s max for timeout, ~36h for delay)
+pub async fn set_auto_txrx(lr2021: &mut Lr2021Stm32, mode: AutoTxrxMode, timeout: Duration, delay: Duration) -> Result<(), Lr2021Error> {
+    let timeout = duration_to_ticks(timeout, TIMEOUT_MAX);
+    let delay = duration_to_ticks(delay, u32::MAX);
+    let req = set_auto_rx_tx_cmd(false, mode, timeout, delay);
+    lr2021.cmd_wr(&req).await
+}
+
+/// Start RX with a timeout (resolution ~30.52us, saturate to ~512s)
+/// A null duration means no timeout (single packet reception)
+pub async fn set_rx_for(lr2021: &mut Lr2021Stm32, timeout: Duration) -> Result<(), Lr2021Error> {
+    lr2021.set_rx(duration_to_ticks(timeout, TIMEOUT_MAX), true).await
+}
+
+/// Start continuous RX: chip stays in RX after each packet received
+pub async fn set_rx_continuous(lr2021: &mut Lr2021Stm32) -> Result<(), Lr2021Error> {
+    lr2021.set_rx(RX_CONTINUOUS, true).await
+}
+
+/// Start TX with a timeout (resolution ~30.52us, saturate to ~512s)
+/// A null duration means no timeout
+pub async fn set_tx_for(lr2021: &mut Lr2021Stm32, timeout: Duration) -> Result<(), Lr2021Error> {
+    lr2021.set_tx(duration_to_ticks(timeout, TIMEOUT_MAX)).await
+}
+
+/// Disable automatic TX/RX
+pub async fn disable_auto_txrx(lr2021: &mut Lr2021Stm32) -> Result<(), Lr2021Error> {
+    let req = set_auto_rx_tx_cmd(true, AutoTxrxMode::Disable, 0, 0);
+    lr2021.cmd_wr(&req).await
+}
+
+/// All packet types supported by the chip
+const PACKET_TYPES: [PacketType; 14] = [
+    PacketType::Lora,
+    PacketType::FskGeneric,
+    PacketType::FskLegacy,
+    PacketType::Ble,
+    PacketType::Ranging,
+    PacketType::Flrc,
+    PacketType::Bpsk,
+    PacketType::LrFhss,
+    PacketType::Wmbus,
+    PacketType::Wisun,
+    PacketType::Ook,
+    PacketType::Raw,
+    PacketType::Zwave,
+    PacketType::Zigbee,
+];
+
+/// Convert a raw packet type value into the enum, None if the value is not a valid packet type
+pub fn packet_type_from_u8(value: u8) -> Option<PacketType> {
+    PACKET_TYPES.iter()
+        .find(|&&p| p as u8 == value)
+        .copied()
+}
+
+/// Read the packet type currently configured in the chip
+/// Useful to check that a set_packet_type was not rejected (e.g. chip not in standby or FS)
+pub async fn get_packet_type(lr2021: &mut Lr2021Stm32) -> Result<Option<PacketType>, Lr2021Error> {
+    let mut rsp = PacketTypeRsp::new();
+    lr2021.cmd_rd(&get_packet_type_req(), rsp.as_mut()).await?;
+    Ok(packet_type_from_u8(rsp.packet_type()))
+}
+
+/// Read the instantaneous RSSI (no averaging window) in raw unit of -0.5dBm
+pub async fn rssi_instant_raw(lr2021: &mut Lr2021Stm32) -> Result<u16, Lr2021Error> {
+    let mut rsp = RssiInstRsp::new();
+    lr2021.cmd_rd(&get_rssi_inst_req(), rsp.as_mut()).await?;
+    Ok(rsp.rssi() as u16)
+}
+
+/// Read the instantaneous RSSI in dBm, rounded to the nearest dB
+/// Use rssi_instant_raw to keep the half-dB resolution
+pub async fn rssi_instant(lr2021: &mut Lr2021Stm32) -> Result<i16, Lr2021Error> {
+    let raw = rssi_instant_raw(lr2021).await?;
+    Ok(-(raw.div_ceil(2) as i16))
+}
+
+/// Select the event stopping the RX timeout: preamble detection or syncword/header detection
+///
+/// Stopping on preamble means any preamble detected keeps the chip in RX until the packet ends (or fails),
+/// which secures packets received with a poor SNR where the syncword is found late, but a false preamble
+/// detection (noise, interferer) keeps the radio busy much longer than the programmed timeout.
+/// Stopping on syncword/header wastes less time on false detections but a real packet whose syncword
+/// arrives after the timeout is lost: the timeout must then cover the full preamble and syncword.
+///
+/// Recommended: stop on syncword/header when the RX timeout is generous (or for duty-cycled listening),
+/// and stop on preamble with tight timeouts, e.g. ranging or ACK windows, where the packet timing is known.
+pub async fn set_rx_timeout_stop(lr2021: &mut Lr2021Stm32, on: StopTimeout) -> Result<(), Lr2021Error> {
+    lr2021.cmd_wr(&set_stop_timeout_cmd(on)).await
+}
+
+/// Maximum gain step in manual gain mode
+pub const RX_GAIN_MAX: u8 = 13;
+
+/// Disable the AGC and set a fixed gain step (1 to 13, clamped)
+/// The gain increases with the step: every dB of gain removed raises the saturation level by the same amount,
+/// so max gain is only safe when strong signals are unlikely (e.g. ADS-B).
+pub async fn set_rx_gain_manual(lr2021: &mut Lr2021Stm32, step: u8) -> Result<(), Lr2021Error> {
+    lr2021.set_rx_gain(step.clamp(1, RX_GAIN_MAX)).await
+}
+
+/// Re-enable the automatic gain control
+pub async fn agc_auto(lr2021: &mut Lr2021Stm32) -> Result<(), Lr2021Error> {
+    lr2021.set_rx_gain(0).await
+}
+
+/// Measure the average RSSI (raw unit of -0.5dBm) from start to stop (included) by step of step Hz
+/// Chip must already be in RX with a bandwidth matching the step.
+/// The dwell time is waited after each retune before measuring.
+/// Stop early when out is full and return the number of samples written.
+pub async fn scan_spectrum(lr2021: &mut Lr2021Stm32, start: u32, stop: u32, step: u32, dwell: Duration, out: &mut [u16]) -> Result<usize, Lr2021Error> {
+    let mut nb = 0;
+    let mut rf = start;
+    for sample in out.iter_mut() {
+        if rf > stop {
+            break;
+        }
+        lr2021.set_rf(rf).await?;
+        Timer::after(dwell).await;
+        *sample = lr2021.get_rssi_avg(16).await?;
+        nb += 1;
+        rf = rf.saturating_add(step.max(1));
+    }
+    Ok(nb)
+}
+
+/// Rolling estimate of the noise floor: exponentially weighted moving average of RSSI measurements
+/// No I/O: feed it with measurements (e.g. cca or rssi_avg) at any cadence.
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub struct NoiseFloor {
+    /// Estimate in 1/16 dBm, None until the first measurement
+    est: Option<i32>,
+    /// Smoothing: each update moves the estimate by 1/2^shift of the difference
+    shift: u8,
+}
+
+impl NoiseFloor {
+    /// Create a tracker with a smoothing factor of 1/2^shift (e.g. 3 averages roughly the last 8 measurements)
+    pub fn new(shift: u8) -> Self {
+        Self {est: None, shift: shift.min(15)}
+    }
+
+    /// Forget all previous measurements
+    pub fn reset(&mut self) {
+        self.est = None;
+    }
+
+    /// Add a measurement in 1/16 dBm
+    fn push(&mut self, m: i32) {
+        self.est = Some(match self.est {
+            None => m,
+            Some(e) => e + ((m - e) >> self.shift),
+        });
+    }
+
+    /// Add a measurement in dBm
+    pub fn update(&mut self, rssi_dbm: i16) {
+        self.push((rssi_dbm as i32) << 4);
+    }
+
+    /// Add a measurement in the raw unit of the chip (-0.5dBm)
+    pub fn update_raw(&mut self, rssi: u16) {
+        self.push(-((rssi as i32) << 3));
+    }
+
+    /// Current estimate in dBm, None if no measurement was done
+    pub fn dbm(&self) -> Option<i16> {
+        self.est.map(|e| ((e + 8) >> 4) as i16)
+    }
+
+    /// Detection threshold in dBm: noise floor plus a margin
+    pub fn margin_threshold(&self, margin_db: i8) -> Option<i16> {
+        self.dbm().map(|n| n + margin_db as i16)
+    }
+}
+
+/// Listen duration of the CAD used for listen-before-talk (~5ms)
+const LBT_CAD_TICKS: u32 = 164;
+/// Maximum time to wait for the end of a CAD
+const LBT_CAD_TIMEOUT: Duration = Duration::from_millis(20);
+
+/// Convert an RSSI threshold in dBm to the raw unit of the chip (-0.5dBm)
+fn rssi_thr_from_dbm(dbm: i16) -> u8 {
+    (-2 * dbm.min(0) as i32).min(255) as u8
+}
+
+/// Listen-before-talk: run an RSSI-based CAD and transmit automatically if the channel is clear
+/// The packet must already be in the TX FIFO. When the channel is busy the CAD is retried
+/// after an increasing backoff, up to max_attempts (no backoff after the last one).
+/// Pending interrupts are cleared before each CAD.
+/// Return true if the transmission was started
+pub async fn tx_with_lbt(lr2021: &mut Lr2021Stm32, threshold_dbm: i16, max_attempts: u8) -> Result<bool, Lr2021Error> {
+    let req = set_cad_params_cmd(LBT_CAD_TICKS, rssi_thr_from_dbm(threshold_dbm), ExitMode::Tx, 0);
+    lr2021.cmd_wr(&req).await?;
+    for attempt in 0..max_attempts {
+        lr2021.get_and_clear_irq().await?;
+        lr2021.cmd_wr(&set_cad_cmd()).await?;
+        // Poll status until CAD is done: the IRQ line might not be mapped on CAD done
+        let start = embassy_time::Instant::now();
+        let intr = loop {
+            let (_, intr) = lr2021.get_status().await?;
+            if intr.cad_done() || start.elapsed() > LBT_CAD_TIMEOUT {
+                break intr;
+            }
+            Timer::after_micros(500).await;
+        };
+        if intr.cad_done() && !intr.cad_detected() {
+            return Ok(true);
+        }
+        if attempt + 1 < max_attempts {
+            Timer::after_millis(5 * (attempt as u64 + 1)).await;
+        }
+    }
+    Ok(false)
+}
+
+/// States of an RF switch control DIO: high when the corresponding TX or RX path is active, low otherwise
+#[derive(Debug, Clone, Copy, Format, PartialEq, Default)]
+pub struct RfSwitchCfg {
+    pub tx_lf: bool,
+    pub rx_lf: bool,
+    pub tx_hf: bool,
+    pub rx_hf: bool,
+    /// DIO level in standby
+    pub standby: bool,
+}
+
+impl RfSwitchCfg {
+    /// DIO low in all modes
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// DIO high in RX on the given path
+    pub fn with_rx(mut self, path: RxPath) -> Self {
+        let is_hf = path as u8 == RxPath::HfPath as u8;
+        self.rx_hf |= is_hf;
+        self.rx_lf |= !is_hf;
+        self
+    }
+
+    /// DIO high in TX with the given power amplifier
+    pub fn with_tx(mut self, pa: PaSel) -> Self {
+        self.tx_hf |= pa == PaSel::Hf;
+        self.tx_lf |= pa == PaSel::Lf;
+        self
+    }
+
+    /// DIO high in TX and RX on the path and PA used at an RF frequency (see rx_path_from_rf and PaSel::from_rf)
+    pub fn for_rf(rf: u32) -> Self {
+        Self::new()
+            .with_rx(rx_path_from_rf(rf))
+            .with_tx(PaSel::from_rf(rf))
+    }
+}
+
+/// Configure a DIO to drive an RF switch (see RfSwitchCfg)
+/// Note: DIO5 and DIO6 have an internal pull enabled by default which is kept in sleep,
+/// an external pull might be needed for the switch to be in a known state when the chip is sleeping.
+pub async fn configure_rf_switch(lr2021: &mut Lr2021Stm32, dio: DioNum, cfg: &RfSwitchCfg) -> Result<(), Lr2021Error> {
+    lr2021.set_dio_function(dio, DioFunc::RfSwitch, PullDrive::PullNone).await?;
+    // Arguments: tx_hf, rx_hf, tx_lf, rx_lf, standby
+    let req = set_dio_rf_switch_config_cmd(dio, cfg.tx_hf, cfg.rx_hf, cfg.tx_lf, cfg.rx_lf, cfg.standby);
+    lr2021.cmd_wr(&req).await
+}
+
+/// Reset the chip and return the source of the previous reset (power-on, external pin, watchdog, ...)
+/// The source is read from the first status after the reset: reading the status clears it,
+/// so it must be called before any other command to get the cause of an unexpected reset.
+/// The fallback mode remembered by set_fallback_mode is cleared.
+pub async fn reset_and_report(lr2021: &mut Lr2021Stm32) -> Result<ResetSrc, Lr2021Error> {
+    lr2021.reset().await?;
+    FALLBACK_MODE.lock(|m| m.set(None));
+    let (status, _) = lr2021.get_status().await?;
+    Ok(status.reset_src())
+}
+
+/// Set of blocks to calibrate, combined with |: CalibBlocks::PLL | CalibBlocks::AAF
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub struct CalibBlocks(u8);
+
+impl CalibBlocks {
+    pub const PA_OFFSET: CalibBlocks = CalibBlocks(1 << 0);
+    pub const MEAS_UNIT: CalibBlocks = CalibBlocks(1 << 1);
+    pub const AAF: CalibBlocks = CalibBlocks(1 << 2);
+    pub const PLL: CalibBlocks = CalibBlocks(1 << 3);
+    pub const HF_RC: CalibBlocks = CalibBlocks(1 << 4);
+    pub const LF_RC: CalibBlocks = CalibBlocks(1 << 5);
+    pub const ALL: CalibBlocks = CalibBlocks(0x3F);
+
+    /// Check if all blocks of other are part of the set
+    pub fn contains(&self, other: CalibBlocks) -> bool {
+        (self.0 & other.0) == other.0
+    }
+}
+
+impl BitOr for CalibBlocks {
+    type Output = CalibBlocks;
+    fn bitor(self, rhs: CalibBlocks) -> CalibBlocks {
+        CalibBlocks(self.0 | rhs.0)
+    }
+}
+
+/// Maximum duration of a calibration
+const CALIB_TIMEOUT: Duration = Duration::from_millis(100);
+/// Calibration failure flags in the GetErrors response (bits 0 to 5, one per calibrated block)
+/// The other flags (oscillator start, PLL lock, missing front-end calibration, ...) are not calibration results
+const CALIB_ERRORS_MASK: u32 = 0x3F;
+
+/// Calibrate a set of blocks and wait for the end of the calibration (chip is then in standby RC)
+/// Error flags are cleared before the calibration and checked after:
+/// return CalibError with the calibration failure flags if any was reported (see CALIB_ERRORS_MASK)
+pub async fn calibrate(lr2021: &mut Lr2021Stm32, blocks: CalibBlocks) -> Result<(), AppError> {
+    lr2021.clear_errors().await?;
+    let req = calibrate_cmd(
+        blocks.contains(CalibBlocks::PA_OFFSET),
+        blocks.contains(CalibBlocks::MEAS_UNIT),
+        blocks.contains(CalibBlocks::AAF),
+        blocks.contains(CalibBlocks::PLL),
+        blocks.contains(CalibBlocks::HF_RC),
+        blocks.contains(CalibBlocks::LF_RC),
+    );
+    lr2021.cmd_wr(&req).await?;
+    lr2021.wait_ready(CALIB_TIMEOUT).await?;
+    let errors = lr2021.get_errors().await?.value() as u32 & CALIB_ERRORS_MASK;
+    if errors != 0 {
+        return Err(AppError::CalibError(errors));
+    }
+    Ok(())
+}
+
+/// Calibrate all blocks, e.g. after a reset (see calibrate)
+pub async fn calibrate_all(lr2021: &mut Lr2021Stm32) -> Result<(), AppError> {
+    calibrate(lr2021, CalibBlocks::ALL).await
+}
+
+/// Enable the end-of-life (low battery) detection with an interrupt on a DIO
+/// The EOL interrupt is raised when the supply drops below the trip voltage selected by threshold
+/// (see the Trim variants for the voltages). The comparison relies on the measurement unit:
+/// it must be calibrated first (calibrate with CalibBlocks::MEAS_UNIT, included in calibrate_all).
+/// The DIO is dedicated to the EOL interrupt: to share a DIO, add IntrFlag::Eol to its interrupt mask instead.
+pub async fn enable_battery_monitor(lr2021: &mut Lr2021Stm32, threshold: Trim, dio: DioNum) -> Result<(), Lr2021Error> {
+    lr2021.cmd_wr(&set_eol_config_cmd(threshold, true)).await?;
+    lr2021.set_dio_irq(dio, IntrFlag::Eol.into()).await
+}
+
+/// Configure a DIO as TX trigger: a rising edge on the DIO starts the TX
+/// On the Nucleo board DIO8 is configured at init and driven by `trigger_tx`
+pub async fn config_tx_trigger(lr2021: &mut Lr2021Stm32, dio: DioNum) -> Result<(), Lr2021Error> {
+    lr2021.set_dio_function(dio, DioFunc::TxTrigger, PullDrive::PullNone).await
+}
+
+/// Start a TX at a precise instant by pulsing the TX trigger pin
+/// The packet must already be in the TX FIFO, and the pin connected to a DIO configured as TX trigger (see config_tx_trigger).
+/// The pin is kept high until the chip is ready plus ~1us to ensure the edge is sampled by the chip.
+/// Accuracy is limited by the executor wake-up latency (a few tens of us).
+pub async fn schedule_tx_at(lr2021: &mut Lr2021Stm32, trigger: &mut Output<'static>, target: Instant) -> Result<(), Lr2021Error> {
+    Timer::at(target).await;
+    trigger.set_high();
+    let res = lr2021.wait_ready(Duration::from_micros(100)).await;
+    Timer::after_micros(1).await;
+    trigger.set_low();
+    res
+}
+
+// TX test modes
+// The chip must be fully configured (RF frequency, PA, TX power, packet type and modulation)
+// before entering a test mode: it then transmits until stop_test is called.
+
+/// Transmit a continuous wave (unmodulated carrier), e.g. to measure output power on a spectrum analyzer
+pub async fn tx_continuous_wave(lr2021: &mut Lr2021Stm32) -> Result<(), Lr2021Error> {
+    lr2021.cmd_wr(&set_tx_test_mode_cmd(TestMode::ContinuousWave)).await
+}
+
+/// Transmit a PRBS9 sequence with the current modulation, e.g. for BER or eye diagram measurement
+pub async fn tx_prbs9(lr2021: &mut Lr2021Stm32) -> Result<(), Lr2021Error> {
+    lr2021.cmd_wr(&set_tx_test_mode_cmd(TestMode::Prbs9)).await
+}
+
+/// Transmit an infinite preamble with the current modulation
+pub async fn tx_infinite_preamble(lr2021: &mut Lr2021Stm32) -> Result<(), Lr2021Error> {
+    lr2021.cmd_wr(&set_tx_test_mode_cmd(TestMode::InfinitePreamble)).await
+}
+
+/// Stop any TX test mode and go back to standby
+pub async fn stop_test(lr2021: &mut Lr2021Stm32) -> Result<(), Lr2021Error> {
+    lr2021.set_chip_mode(ChipMode::StandbyRc).await
+}
+
+/// Limit between LF (sub-GHz) and HF (2.4GHz) front-ends, used for both the RX path and the power amplifier
+const PA_HF_MIN_RF: u32 = 1_500_000_000;
+
+/// Select the RX path compatible with an RF frequency:
+/// LF path below 1.5GHz (sub-GHz bands up to ~1.1GHz), HF path above (2.4GHz band).
+/// The chip does not switch automatically: a wrong path does not raise any error but kills the sensitivity.
+pub fn rx_path_from_rf(rf: u32) -> RxPath {
+    if rf >= PA_HF_MIN_RF {RxPath::HfPath} else {RxPath::LfPath}
+}
+
+/// Set the RF frequency and the matching RX path (see rx_path_from_rf)
+pub async fn set_rf_auto_path(lr2021: &mut Lr2021Stm32, rf: u32, boost: RxBoost) -> Result<(), Lr2021Error> {
+    lr2021.set_rf(rf).await?;
+    lr2021.set_rx_path(rx_path_from_rf(rf), boost).await
+}
+
+/// Automatic frequency correction after an FSK packet reception: move the RF frequency toward the frequency of the received signal
+/// Only for the packet types reporting their frequency error in the FSK packet status (FSK, wM-Bus and WiSUN),
+/// other packet types return UnsupportedPacketType. rf is the frequency currently programmed, updated with the correction.
+/// freq_offset is taken as a value in Hz, positive when the signal was received above rf, and is added to rf:
+/// if the correction moves the frequency away from the transmitter, the sign is the other way around.
+/// Only half of the error is applied at each call: with a noisy estimate the frequency converges
+/// in a few packets instead of jumping around. Errors within the deadband are ignored to avoid
+/// oscillating around the target.
+/// Return the correction applied to rf in Hz (0 when in the deadband)
+pub async fn afc_correct_fsk(lr2021: &mut Lr2021Stm32, rf: &mut u32, deadband_hz: u32) -> Result<i32, AppError> {
+    let offset_hz = match get_packet_type(lr2021).await? {
+        Some(PacketType::FskGeneric) |
+        Some(PacketType::FskLegacy) |
+        Some(PacketType::Wmbus) |
+        Some(PacketType::Wisun) => lr2021.get_fsk_packet_status().await?.freq_offset() as i32,
+        Some(p) => return Err(AppError::UnsupportedPacketType(p as u8)),
+        None => return Err(AppError::UnsupportedPacketType(0xFF)),
+    };
+    if offset_hz.unsigned_abs() <= deadband_hz {
+        return Ok(0);
+    }
+    let step = offset_hz / 2;
+    *rf = rf.saturating_add_signed(step);
+    lr2021.set_rf(*rf).await?;
+    Ok(step)
+}
+
+/// Resolution of the front-end calibration frequencies
+const CALIB_FE_STEP_HZ: u32 = 4_000_000;
+/// Maximum number of frequencies calibrated by calib_fe
+pub const CALIB_FE_FREQ_MAX: usize = 3;
+
+/// Front-end calibration (image rejection) covering a frequency range, e.g. a hopping band:
+/// the range is split in three segments calibrated at their center (single point for a range under 12MHz).
+pub async fn calib_fe_range(lr2021: &mut Lr2021Stm32, start: u32, stop: u32) -> Result<(), Lr2021Error> {
+    let span = stop.saturating_sub(start);
+    let nb = if span < CALIB_FE_STEP_HZ * CALIB_FE_FREQ_MAX as u32 {1} else {CALIB_FE_FREQ_MAX};
+    let mut freqs = [0u16; CALIB_FE_FREQ_MAX];
+    for (i, f) in freqs.iter_mut().take(nb).enumerate() {
+        let center = start + span / (2 * nb as u32) * (2 * i as u32 + 1);
+        *f = ((center + CALIB_FE_STEP_HZ / 2) / CALIB_FE_STEP_HZ) as u16;
+    }
+    lr2021.calib_fe(&freqs[..nb]).await
+}
+
+/// Power amplifier selection
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub enum PaSel {
+    /// Sub-GHz power amplifier
+    Lf,
+    /// 2.4GHz power amplifier
+    Hf,
+}
+
+impl PaSel {
+    /// Select the power amplifier compatible with an RF frequency
+    pub fn from_rf(rf: u32) -> Self {
+        if rf >= PA_HF_MIN_RF {PaSel::Hf} else {PaSel::Lf}
+    }
+}
+
+/// Last fallback mode set with set_fallback_mode (single chip: the board has only one LR2021)
+static FALLBACK_MODE: Mutex<CriticalSectionRawMutex, Cell<Option<FallbackMode>>> = Mutex::new(Cell::new(None));
+
+/// Set the mode the chip goes to after TX/RX and remember it (see fallback_mode)
+pub async fn set_fallback_mode(lr2021: &mut Lr2021Stm32, mode: FallbackMode) -> Result<(), Lr2021Error> {
+    lr2021.set_fallback(mode).await?;
+    FALLBACK_MODE.lock(|m| m.set(Some(mode)));
+    Ok(())
+}
+
+/// Last fallback mode set with set_fallback_mode, None if unknown
+/// The chip has no command to read the fallback mode back, so only set_fallback_mode updates this value:
+/// a direct call to lr2021.set_fallback is not seen and leaves it stale.
+/// reset_and_report clears it (None), a reset done by other means (lr2021.reset, brown-out, ...) does not.
+pub fn fallback_mode() -> Option<FallbackMode> {
+    FALLBACK_MODE.lock(|m| m.get())
+}
+
+/// Radio configuration applied by init_radio
+#[derive(Debug, Clone, Copy)]
+pub struct RadioInitConfig {
+    /// RF frequency in Hz
+    pub rf: u32,
+    /// RX path: None to select it from the frequency (see rx_path_from_rf)
+    pub rx_path: Option<RxPath>,
+    pub rx_boost: RxBoost,
+    /// TX power given to set_tx_params (the power amplifier is left to the caller)
+    pub tx_power: i8,
+    pub ramp: RampTime,
+    /// Mode after TX/RX: None to keep the chip default
+    pub fallback: Option<FallbackMode>,
+}
+
+impl RadioInitConfig {
+    /// Configuration for a frequency with automatic RX path, no boost, 0dBm, 8us ramp and default fallback
+    pub fn new(rf: u32) -> Self {
+        Self {
+            rf,
+            rx_path: None,
+            rx_boost: RxBoost::Off,
+            tx_power: 0,
+            ramp: RampTime::Ramp8u,
+            fallback: None,
+        }
+    }
+}
+
+/// Canonical bring-up of the radio after reset: RF frequency, RX path, front-end calibration,
+/// TX parameters, fallback mode. Power amplifier, packet type and modulation are left to the caller.
+/// The configuration is checked before sending any command: RxPathMismatch if the RX path does not match the frequency.
+/// Return the firmware version
+pub async fn init_radio(lr2021: &mut Lr2021Stm32, cfg: &RadioInitConfig) -> Result<VersionRsp, AppError> {
+    let rx_path = rx_path_from_rf(cfg.rf);
+    if cfg.rx_path.is_some_and(|p| p as u8 != rx_path as u8) {
+        return Err(AppError::RxPathMismatch(cfg.rf));
+    }
+    let version = lr2021.get_version().await?;
+    lr2021.set_rf(cfg.rf).await?;
+    lr2021.set_rx_path(rx_path, cfg.rx_boost).await?;
+    lr2021.calib_fe(&[]).await?;
+    lr2021.set_tx_params(cfg.tx_power, cfg.ramp).await?;
+    if let Some(mode) = cfg.fallback {
+        set_fallback_mode(lr2021, mode).await?;
+    }
+    Ok(version)
+}
@@ -0,0 +1,382 @@
+use defmt::{write, Format, Formatter};
+use embassy_stm32::exti::ExtiInput;
+use embassy_time::{with_timeout, Duration};
+use heapless::Vec;
+use lr2021::{lora::{LoraBw, LoraModulationParams, Sf}, radio::PacketType, system::ChipMode, Lr2021Error};
+
+use crate::{board::Lr2021Stm32, error::AppError, radio_utils::{calib_fe_range, lora_bw_to_hz, sf_to_u8}};
+
+/// Half the speed of light in cm/s: ranging result is a round-trip time of flight
+const HALF_C_CM_S: i64 = 15_000_000_000;
+
+/// Extended ranging measurement
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub struct RangingMeas {
+    /// Raw round-trip time of flight from the first exchange
+    pub rng1: i32,
+    /// Raw round-trip time of flight from the second exchange
+    pub rng2: i32,
+    /// Raw RSSI of the response (without offset)
+    pub rssi1: u8,
+}
+
+impl RangingMeas {
+    /// Raw round-trip time of flight: average of the two measurements
+    pub fn rttof(&self) -> i32 {
+        ((self.rng1 as i64 + self.rng2 as i64) / 2) as i32
+    }
+
+    /// Raw doppler estimation: difference between the two measurements
+    pub fn doppler(&self) -> i32 {
+        self.rng2 - self.rng1
+    }
+
+    /// RSSI of the response in dBm, corrected with the offset given by get_ranging_rssi_offset
+    pub fn rssi1_dbm(&self, offset: i16) -> i16 {
+        offset + self.rssi1 as i16
+    }
+
+    /// Distance in cm, based on the average of the two measurements
+    /// Resolution is inversely proportional to the bandwidth: one LSB is c/(2*4096*BW)
+    pub fn distance_cm(&self, bw: LoraBw) -> i32 {
+        ((self.rttof() as i64 * HALF_C_CM_S) / (4096 * lora_bw_to_hz(bw) as i64)) as i32
+    }
+
+    /// Relative speed in km/h estimated from the difference between the two measurements
+    /// speed_kmh = doppler * BW/(1<<sf) * c/2*3.6 / 4096 / RF
+    pub fn speed_kmh(&self, bw: LoraBw, sf: Sf, rf: u32) -> f32 {
+        let doppler = self.doppler() as f32;
+        let bin_hz = lora_bw_to_hz(bw) as f32 / (1u32 << sf_to_u8(sf)) as f32;
+        doppler * bin_hz * 1.5e8 * 3.6 / 4096.0 / rf as f32
+    }
+}
+
+/// Read the extended ranging result
+pub async fn get_ranging_meas(lr2021: &mut Lr2021Stm32) -> Result<RangingMeas, Lr2021Error> {
+    let result = lr2021.get_ranging_ext_result().await?;
+    Ok(RangingMeas {
+        rng1: result.rng1() as i32,
+        rng2: result.rng2() as i32,
+        rssi1: result.rssi1() as u8,
+    })
+}
+
+/// Role of the device in a ranging exchange
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub enum RangingRole {
+    /// Send ranging requests and measure the round-trip time of flight
+    Initiator,
+    /// Answer ranging requests matching its device address
+    Responder,
+}
+
+impl RangingRole {
+    pub fn is_initiator(&self) -> bool {
+        *self == RangingRole::Initiator
+    }
+
+    pub fn toggle(&mut self) {
+        *self = match self {
+            RangingRole::Initiator => RangingRole::Responder,
+            RangingRole::Responder => RangingRole::Initiator,
+        }
+    }
+}
+
+/// Ranging addresses and role
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub struct RangingConfig {
+    /// Address of the device: a responder only answers requests with this address
+    pub dev_addr: u32,
+    /// Address sent in the requests of an initiator (device address of the responder)
+    pub req_addr: u32,
+    /// Number of address bytes checked by the responder (None to check all 4 bytes)
+    pub addr_len: Option<u8>,
+    pub role: RangingRole,
+}
+
+/// Configure the ranging modulation for the role, the device address and the request address
+/// Both addresses are always set, so switching role only requires to change cfg.role and call it again.
+/// Packet type must be set to Ranging
+pub async fn configure_ranging(lr2021: &mut Lr2021Stm32, modulation: &LoraModulationParams, cfg: &RangingConfig) -> Result<(), Lr2021Error> {
+    lr2021.set_ranging_modulation(modulation, cfg.role.is_initiator()).await?;
+    lr2021.set_ranging_dev_addr(cfg.dev_addr, cfg.addr_len).await?;
+    lr2021.set_ranging_req_addr(cfg.req_addr).await
+}
+
+/// Select the ranging packet type and configure it (see configure_ranging)
+/// The RF patch of the driver (patch_ranging_rf) is applied right after selecting the packet type:
+/// it adjusts RF settings the firmware does not set by itself in ranging, and without it exchanges
+/// silently fail or give wrong distances. Its content is not documented by the driver: it is applied
+/// once here, channel changes being done with set_rf_ranging (see ranging_hop).
+pub async fn setup_ranging(lr2021: &mut Lr2021Stm32, modulation: &LoraModulationParams, cfg: &RangingConfig) -> Result<(), Lr2021Error> {
+    lr2021.set_packet_type(PacketType::Ranging).await?;
+    lr2021.patch_ranging_rf().await?;
+    configure_ranging(lr2021, modulation, cfg).await
+}
+
+/// Ranging exchange statistics
+/// Initiator counters: request_valid (requests sent), exchange_valid, timeout.
+/// Responder counters: response_done, request_discarded (address mismatch).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RangingStats {
+    pub exchange_valid: u16,
+    pub request_valid: u16,
+    pub response_done: u16,
+    pub timeout: u16,
+    pub request_discarded: u16,
+}
+
+impl RangingStats {
+    /// Percentage of valid exchanges over the requests sent (initiator only), 0 when no request was sent
+    pub fn success_rate(&self) -> u8 {
+        if self.request_valid == 0 {
+            return 0;
+        }
+        ((self.exchange_valid.min(self.request_valid) as u32 * 100) / self.request_valid as u32) as u8
+    }
+}
+
+impl Format for RangingStats {
+    fn format(&self, fmt: Formatter) {
+        write!(fmt, "Exchange={}/{} ({}%), Timeout={} | Response={}, Discard={}",
+            self.exchange_valid, self.request_valid, self.success_rate(), self.timeout,
+            self.response_done, self.request_discarded
+        );
+    }
+}
+
+/// Read the ranging statistics
+pub async fn get_ranging_stats(lr2021: &mut Lr2021Stm32) -> Result<RangingStats, Lr2021Error> {
+    let stats = lr2021.get_ranging_stats().await?;
+    Ok(RangingStats {
+        exchange_valid: stats.exchange_valid() as u16,
+        request_valid: stats.request_valid() as u16,
+        response_done: stats.response_done() as u16,
+        timeout: stats.timeout() as u16,
+        request_discarded: stats.request_discarded() as u16,
+    })
+}
+
+/// Offset applied on the base delay when no calibration is available for a configuration
+pub const DEFAULT_DELAY_OFFSET: i16 = 10;
+/// Maximum number of configurations stored in the calibration table
+pub const DELAY_CAL_SIZE: usize = 16;
+/// Size in bytes of one serialized calibration entry
+const DELAY_CAL_ENTRY_BYTES: usize = 4;
+
+/// Ranging TX/RX delay offset for one SF/BW configuration
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub struct DelayCalEntry {
+    /// Spreading factor (5 to 12)
+    pub sf: u8,
+    /// LoRa bandwidth (raw value)
+    pub bw: u8,
+    /// Offset subtracted from the base delay
+    pub offset: i16,
+}
+
+/// Ranging delay calibration table, keyed by SF/BW
+/// The offset compensates the PCB and front-end delay on top of the base delay provided by the driver.
+#[derive(Debug, Clone, Default)]
+pub struct RangingDelayCal {
+    entries: Vec<DelayCalEntry, DELAY_CAL_SIZE>,
+}
+
+impl RangingDelayCal {
+    /// Create an empty calibration table
+    pub fn new() -> Self {
+        Self { entries: Vec::new() }
+    }
+
+    /// Offset for a given configuration, default to DEFAULT_DELAY_OFFSET when not calibrated
+    pub fn offset(&self, sf: Sf, bw: LoraBw) -> i16 {
+        let sf = sf_to_u8(sf);
+        self.entries.iter()
+            .find(|e| e.sf == sf && e.bw == bw as u8)
+            .map(|e| e.offset)
+            .unwrap_or(DEFAULT_DELAY_OFFSET)
+    }
+
+    /// Store the offset of a configuration, replacing any previous value
+    /// Return false if the table is full
+    pub fn set_offset(&mut self, sf: Sf, bw: LoraBw, offset: i16) -> bool {
+        let entry = DelayCalEntry { sf: sf_to_u8(sf), bw: bw as u8, offset };
+        match self.entries.iter_mut().find(|e| e.sf == entry.sf && e.bw == entry.bw) {
+            Some(e) => {
+                e.offset = offset;
+                true
+            }
+            None => self.entries.push(entry).is_ok(),
+        }
+    }
+
+    /// Set the ranging TX/RX delay for a configuration using the calibrated offset
+    /// The table can come from untrusted bytes (see from_bytes): the delay is clamped to 0
+    /// when the offset is larger than the base delay.
+    /// Return the delay programmed
+    pub async fn apply(&self, lr2021: &mut Lr2021Stm32, sf: Sf, bw: LoraBw) -> Result<u32, Lr2021Error> {
+        let modulation = LoraModulationParams::basic(sf, bw);
+        let base = lr2021.get_ranging_base_delay(&modulation);
+        let delay = base.saturating_add_signed(-(self.offset(sf, bw) as i32));
+        lr2021.set_ranging_txrx_delay(delay).await?;
+        Ok(delay)
+    }
+
+    /// Serialize the table (4 bytes per entry: SF, BW, offset in little endian)
+    /// Return the number of bytes written
+    pub fn to_bytes(&self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        for (e, chunk) in self.entries.iter().zip(buf.chunks_exact_mut(DELAY_CAL_ENTRY_BYTES)) {
+            let offset = e.offset.to_le_bytes();
+            chunk.copy_from_slice(&[e.sf, e.bw, offset[0], offset[1]]);
+            len += DELAY_CAL_ENTRY_BYTES;
+        }
+        len
+    }
+
+    /// Rebuild a table from bytes generated by to_bytes
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut cal = Self::new();
+        for chunk in bytes.chunks_exact(DELAY_CAL_ENTRY_BYTES) {
+            let entry = DelayCalEntry {
+                sf: chunk[0],
+                bw: chunk[1],
+                offset: i16::from_le_bytes([chunk[2], chunk[3]]),
+            };
+            if cal.entries.push(entry).is_err() {
+                break;
+            }
+        }
+        cal
+    }
+}
+
+/// Step in delay offset used to estimate the distance sensitivity during calibration
+const CAL_OFFSET_STEP: i16 = 16;
+
+/// Run a burst of ranging exchanges as initiator and return the average distance in cm
+/// Return None if no exchange was valid
+async fn avg_distance(lr2021: &mut Lr2021Stm32, irq: &mut ExtiInput<'static>, bw: LoraBw, nb_meas: u8) -> Result<Option<i32>, Lr2021Error> {
+    let mut sum = 0;
+    let mut cnt = 0;
+    for _ in 0..nb_meas {
+        lr2021.set_tx(0).await?;
+        if with_timeout(Duration::from_millis(500), irq.wait_for_rising_edge()).await.is_err() {
+            continue;
+        }
+        let intr = lr2021.get_and_clear_irq().await?;
+        if intr.rng_exch_vld() {
+            sum += get_ranging_meas(lr2021).await?.distance_cm(bw);
+            cnt += 1;
+        }
+    }
+    Ok(if cnt > 0 {Some(sum / cnt)} else {None})
+}
+
+/// Estimate the delay offset for a SF/BW configuration using a responder placed at a known distance
+/// The device must be configured as ranging initiator with DIO7 set on RngExchVld/RngTimeout.
+/// The distance is measured with two different offsets to estimate the sensitivity,
+/// and the offset giving the reference distance is stored in the calibration table.
+/// The second offset is above the current one, or below when it would overflow.
+/// Return the new offset or None if the responder did not answer or the offset found does not fit in an i16.
+pub async fn calibrate_ranging_delay(
+    lr2021: &mut Lr2021Stm32,
+    irq: &mut ExtiInput<'static>,
+    cal: &mut RangingDelayCal,
+    sf: Sf,
+    bw: LoraBw,
+    ref_dist_cm: i32,
+    nb_meas: u8
+) -> Result<Option<i16>, Lr2021Error> {
+    let offset0 = cal.offset(sf, bw);
+    cal.apply(lr2021, sf, bw).await?;
+    let Some(dist0) = avg_distance(lr2021, irq, bw, nb_meas).await? else {
+        return Ok(None);
+    };
+
+    let step = if offset0 > i16::MAX - CAL_OFFSET_STEP {-CAL_OFFSET_STEP} else {CAL_OFFSET_STEP};
+    let mut cal_step = cal.clone();
+    cal_step.set_offset(sf, bw, offset0 + step);
+    cal_step.apply(lr2021, sf, bw).await?;
+    let dist1 = avg_distance(lr2021, irq, bw, nb_meas).await?;
+
+    let offset = match dist1 {
+        Some(dist1) if dist1 != dist0 => {
+            // Computed on 64 bits: the distance differences and their product with the step can overflow an i32
+            let corr = (ref_dist_cm as i64 - dist0 as i64) * step as i64 / (dist1 as i64 - dist0 as i64);
+            i16::try_from(offset0 as i64 + corr).ok()
+        }
+        _ => None,
+    };
+    if let Some(offset) = offset {
+        cal.set_offset(sf, bw, offset);
+    }
+    cal.apply(lr2021, sf, bw).await?;
+    Ok(offset)
+}
+
+/// Frequency hopping plan: RF channel goes from start to stop by step, then wraps around
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub struct FreqHopPlan {
+    pub start: u32,
+    pub stop: u32,
+    pub step: u32,
+    rf: u32,
+}
+
+impl FreqHopPlan {
+    /// Create a plan starting on the first channel
+    pub fn new(start: u32, stop: u32, step: u32) -> Self {
+        Self { start, stop, step, rf: start }
+    }
+
+    /// Current RF channel
+    pub fn current(&self) -> u32 {
+        self.rf
+    }
+
+    /// Go back to first channel
+    pub fn reset(&mut self) {
+        self.rf = self.start;
+    }
+
+    /// Move to the next channel, wrapping back to start after stop
+    pub fn hop(&mut self) -> u32 {
+        self.rf += self.step;
+        if self.rf > self.stop {
+            self.rf = self.start;
+        }
+        self.rf
+    }
+
+    /// Return true if the current channel is the first one
+    pub fn is_start(&self) -> bool {
+        self.rf == self.start
+    }
+
+    /// Run the front-end calibration over the whole plan, to be done before hopping
+    pub async fn calibrate(&self, lr2021: &mut Lr2021Stm32) -> Result<(), Lr2021Error> {
+        calib_fe_range(lr2021, self.start, self.stop).await
+    }
+}
+
+/// Change the ranging RF channel to the next one in the hopping plan
+/// Ranging uses set_rf_ranging instead of set_rf to keep the ranging RF settings (see setup_ranging) on the new channel.
+/// The image calibration is not redone on each hop: the chip flags a frequency outside the calibrated range,
+/// which degrades the accuracy, and NotCalibrated is then returned (see FreqHopPlan::calibrate).
+/// A responder goes through FS before changing channel: caller is in charge of restarting RX.
+/// Return the new RF channel
+pub async fn ranging_hop(lr2021: &mut Lr2021Stm32, plan: &mut FreqHopPlan, is_initiator: bool) -> Result<u32, AppError> {
+    let rf = plan.hop();
+    if !is_initiator {
+        lr2021.set_chip_mode(ChipMode::Fs).await?;
+    }
+    lr2021.set_rf_ranging(rf).await?;
+    let errors = lr2021.get_errors().await?;
+    if errors.rxfreq_no_fe_cal() {
+        lr2021.clear_errors().await?;
+        return Err(AppError::NotCalibrated(rf));
+    }
+    Ok(rf)
+}
@@ -0,0 +1,111 @@
+use defmt::Format;
+use lr2021::{regmem::{read_reg_mem32_req, write_reg_mem32_cmd}, Lr2021Error};
+
+use crate::board::Lr2021Stm32;
+
+/// Maximum number of 32-bit words per register access command
+pub const REG_BURST_MAX: usize = 32;
+/// Size of the WriteRegMem32 header: opcode and 24-bit address
+const REGMEM_HDR_LEN: usize = 5;
+/// Size of the status at the start of every response (see lr2021::status::Status)
+const RSP_STATUS_LEN: usize = 2;
+
+/// Read consecutive 32-bit registers starting at addr
+/// The chip auto-increments the address by 4 after each word, and words are transferred MSB first.
+/// Accesses larger than REG_BURST_MAX words are split in multiple commands.
+pub async fn rd_reg_burst(lr2021: &mut Lr2021Stm32, addr: u32, dst: &mut [u32]) -> Result<(), Lr2021Error> {
+    let mut addr = addr;
+    for chunk in dst.chunks_mut(REG_BURST_MAX) {
+        let req = read_reg_mem32_req(addr, chunk.len() as u8);
+        let mut rsp = [0u8; RSP_STATUS_LEN + 4 * REG_BURST_MAX];
+        let rsp_len = RSP_STATUS_LEN + 4 * chunk.len();
+        lr2021.cmd_rd(&req, &mut rsp[..rsp_len]).await?;
+        for (v, bytes) in chunk.iter_mut().zip(rsp[RSP_STATUS_LEN..rsp_len].chunks_exact(4)) {
+            *v = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        addr += 4 * chunk.len() as u32;
+    }
+    Ok(())
+}
+
+/// Write consecutive 32-bit registers starting at addr
+/// The chip auto-increments the address by 4 after each word, and words are transferred MSB first.
+/// Accesses larger than REG_BURST_MAX words are split in multiple commands.
+pub async fn wr_reg_burst(lr2021: &mut Lr2021Stm32, addr: u32, vals: &[u32]) -> Result<(), Lr2021Error> {
+    let mut addr = addr;
+    for chunk in vals.chunks(REG_BURST_MAX) {
+        // Single word command from the driver provides the header, followed by all the words
+        let mut req = [0u8; REGMEM_HDR_LEN + 4 * REG_BURST_MAX];
+        req[..REGMEM_HDR_LEN].copy_from_slice(&write_reg_mem32_cmd(addr, 0)[..REGMEM_HDR_LEN]);
+        for (bytes, v) in req[REGMEM_HDR_LEN..].chunks_exact_mut(4).zip(chunk) {
+            bytes.copy_from_slice(&v.to_be_bytes());
+        }
+        lr2021.cmd_wr(&req[..REGMEM_HDR_LEN + 4 * chunk.len()]).await?;
+        addr += 4 * chunk.len() as u32;
+    }
+    Ok(())
+}
+
+/// Replace the bits set in mask by the ones in value
+pub fn reg_merge(old: u32, mask: u32, value: u32) -> u32 {
+    (old & !mask) | (value & mask)
+}
+
+/// Read-modify-write of a 32-bit register: bits set in mask are replaced by the ones in value
+/// Return the new register value
+pub async fn modify_reg(lr2021: &mut Lr2021Stm32, addr: u32, mask: u32, value: u32) -> Result<u32, Lr2021Error> {
+    let old = lr2021.rd_reg(addr).await?;
+    let new = reg_merge(old, mask, value);
+    lr2021.wr_reg(addr, new).await?;
+    Ok(new)
+}
+
+/// Address of the RSSI configuration register
+pub const REG_RSSI_CFG: u32 = 0xF3014C;
+
+const RSSI_CFG_RES_POS: u32 = 3;
+const RSSI_CFG_RES_MASK: u32 = 0x7 << RSSI_CFG_RES_POS;
+const RSSI_CFG_AVG_POS: u32 = 8;
+const RSSI_CFG_AVG_MASK: u32 = 0xF << RSSI_CFG_AVG_POS;
+
+/// RSSI configuration register (0xF3014C)
+///
+/// | Bits  | Field      | Description                                   |
+/// |-------|------------|-----------------------------------------------|
+/// | 11:8  | avg_window | Averaging window (log2 of number of samples)  |
+/// | 5:3   | resolution | Measurement resolution: 7 for fine measurement|
+///
+/// Other bits are reserved and preserved on write.
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub struct RssiConfig {
+    pub avg_window: u8,
+    pub resolution: u8,
+}
+
+impl RssiConfig {
+    /// Decode the register value
+    pub fn from_reg(value: u32) -> Self {
+        Self {
+            avg_window: ((value & RSSI_CFG_AVG_MASK) >> RSSI_CFG_AVG_POS) as u8,
+            resolution: ((value & RSSI_CFG_RES_MASK) >> RSSI_CFG_RES_POS) as u8,
+        }
+    }
+
+    /// Register value of the fields (reserved bits at 0)
+    pub fn to_reg(&self) -> u32 {
+        (((self.avg_window as u32) << RSSI_CFG_AVG_POS) & RSSI_CFG_AVG_MASK) |
+        (((self.resolution as u32) << RSSI_CFG_RES_POS) & RSSI_CFG_RES_MASK)
+    }
+}
+
+/// Read the RSSI configuration
+pub async fn get_rssi_config(lr2021: &mut Lr2021Stm32) -> Result<RssiConfig, Lr2021Error> {
+    let value = lr2021.rd_reg(REG_RSSI_CFG).await?;
+    Ok(RssiConfig::from_reg(value))
+}
+
+/// Change the RSSI configuration, keeping reserved bits unchanged
+pub async fn set_rssi_config(lr2021: &mut Lr2021Stm32, cfg: &RssiConfig) -> Result<(), Lr2021Error> {
+    modify_reg(lr2021, REG_RSSI_CFG, RSSI_CFG_AVG_MASK | RSSI_CFG_RES_MASK, cfg.to_reg()).await?;
+    Ok(())
+}
@@ -0,0 +1,44 @@
+use lr2021::{system::{set_ntc_params_cmd, set_temp_comp_cfg_cmd, AdcRes, CompMode, TempSrc}, Lr2021Error};
+
+use crate::{board::Lr2021Stm32, error::AppError};
+
+/// Configure the external NTC used by get_temperature with TempSrc::Ntc
+///
+/// The NTC is biased by the chip and measured against a reference resistor:
+///  - beta: Beta constant of the NTC in Kelvin, sent as is (typically 3000 to 4500)
+///  - r_ratio: ratio between the NTC resistance at 25°C and the reference resistance, raw command encoding
+///  - delay: delay between the bias enable and the measurement, raw command encoding, to let the voltage settle
+///
+/// Example for a common 10kΩ NTC (B25/85 = 3435K) with a 10kΩ reference: beta = 3435 and r_ratio encoding a ratio of 1.0.
+/// The fixed point format of r_ratio and the unit of delay are given with SetNtcParams in the LR2021 user manual.
+/// Return OutOfRange(0) for a null delay: the measurement would be done before the bias settled
+pub async fn configure_ntc(lr2021: &mut Lr2021Stm32, beta: u16, r_ratio: u16, delay: u8) -> Result<(), AppError> {
+    if delay == 0 {
+        return Err(AppError::OutOfRange(0));
+    }
+    lr2021.cmd_wr(&set_ntc_params_cmd(r_ratio, beta, delay)).await?;
+    Ok(())
+}
+
+/// Temperature measured by the external NTC in Celsius (rounded down), NTC must be configured (see configure_ntc)
+pub async fn read_ntc_temp(lr2021: &mut Lr2021Stm32) -> Result<i16, Lr2021Error> {
+    let t = lr2021.get_temperature(TempSrc::Ntc, AdcRes::Res13bit).await?;
+    // Temperature unit is 1/32 °C
+    Ok((t as i16) >> 5)
+}
+
+/// Enable the temperature compensation of the chip (or disable it with CompMode::Disabled)
+///
+/// The chip measures the temperature and corrects the settings drifting with it,
+/// mainly the frequency (crystal/TCXO drift) and the PA output power:
+///  - Relative: corrects the variation since the last calibration, the reference being the temperature
+///    measured at that time. Suited when the chip is calibrated in its operating environment.
+///  - Absolute: corrects from the absolute temperature measured, independently of the calibration,
+///    for deployments with large temperature swings between calibrations.
+///
+/// The source is either the internal Vbe sensor (die temperature, no external part, reflects the chip
+/// self-heating during TX) or an external NTC placed close to the crystal (see configure_ntc),
+/// which follows the crystal temperature more accurately on boards with a bare crystal.
+pub async fn enable_temp_compensation(lr2021: &mut Lr2021Stm32, mode: CompMode, source: TempSrc) -> Result<(), Lr2021Error> {
+    lr2021.cmd_wr(&set_temp_comp_cfg_cmd(mode, source)).await
+}
@@ -0,0 +1,68 @@
+use heapless::Vec;
+
+/// Maximum number of numeric arguments in a command
+pub const CMD_ARGS_MAX: usize = 4;
+
+/// Command received on the UART: one letter (case-insensitive) followed by optional numbers
+/// separated by any non-digit character, e.g. "R400-1_000" gives 'r' with arguments [400, 1000]
+#[derive(Debug, Clone, PartialEq)]
+pub struct CmdLine {
+    /// Command letter, in lowercase
+    pub letter: u8,
+    /// Numeric arguments (extra arguments are dropped)
+    pub args: Vec<u32, CMD_ARGS_MAX>,
+}
+
+impl CmdLine {
+    /// Tokenize a command: None if it does not start with a letter.
+    /// Parsing stops on the first null byte, i.e. the unused part of a receive buffer
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let (&letter, mut rest) = bytes.split_first()?;
+        if !letter.is_ascii_alphabetic() {
+            return None;
+        }
+        let mut args = Vec::new();
+        while let Some(&c) = rest.first() {
+            if c == 0 {
+                break;
+            }
+            if c.is_ascii_digit() {
+                let (v, len) = parse_num(rest);
+                args.push(v).ok();
+                rest = &rest[len..];
+            } else {
+                rest = &rest[1..];
+            }
+        }
+        Some(Self {letter: letter.to_ascii_lowercase(), args})
+    }
+
+    /// Argument at index idx
+    pub fn arg(&self, idx: usize) -> Option<u32> {
+        self.args.get(idx).copied()
+    }
+}
+
+/// Parse a decimal number with optional '_' separators (e.g. 1_000)
+/// Return the value and the number of bytes consumed (the character ending the number is not consumed)
+pub fn parse_num(buffer: &[u8]) -> (u32, usize) {
+    let mut v = 0u32;
+    let mut idx = 0;
+    for &c in buffer {
+        match c {
+            b'0'..=b'9' => v = v.wrapping_mul(10).wrapping_add((c - b'0') as u32),
+            b'_' => {}
+            _ => break,
+        }
+        idx += 1;
+    }
+    (v, idx)
+}
+
+/// Find the command associated to the letter of a command line in a table of (letter, command)
+/// Letters in the table must be lowercase
+pub fn dispatch<C: Copy>(commands: &[(u8, C)], line: &CmdLine) -> Option<C> {
+    commands.iter()
+        .find(|(l, _)| *l == line.letter)
+        .map(|&(_, c)| c)
+}
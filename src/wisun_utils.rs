@@ -0,0 +1,185 @@
+use defmt::Format;
+use lr2021::wisun::{WisunMode, WisunPacketParams};
+
+use crate::{board::Lr2021Stm32, error::AppError, radio_utils::rx_bw_from_hz};
+
+/// Modulation parameters of a WiSUN FSK operating mode (IEEE 802.15.4g SUN FSK PHY)
+/// The modulation index is h = 2 * deviation / bitrate.
+///
+/// | Mode | Bitrate  | Mod. index | Deviation | RX BW (Carson) |
+/// |------|----------|------------|-----------|----------------|
+/// | 1a   |  50 kb/s |    0.5     | 12.5 kHz  |  75 kHz        |
+/// | 1b   |  50 kb/s |    1.0     |   25 kHz  | 100 kHz        |
+/// | 2a   | 100 kb/s |    0.5     |   25 kHz  | 150 kHz        |
+/// | 2b   | 100 kb/s |    1.0     |   50 kHz  | 200 kHz        |
+/// | 3    | 150 kb/s |    0.5     | 37.5 kHz  | 225 kHz        |
+/// | 4a   | 200 kb/s |    0.5     |   50 kHz  | 300 kHz        |
+/// | 4b   | 200 kb/s |    1.0     |  100 kHz  | 400 kHz        |
+/// | 5    | 300 kb/s |    0.5     |   75 kHz  | 450 kHz        |
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub struct WisunModeParams {
+    /// Bitrate in b/s
+    pub bitrate: u32,
+    /// Frequency deviation in Hz
+    pub deviation: u32,
+    /// RX bandwidth in Hz: Carson bandwidth 2 * deviation + bitrate
+    pub rx_bw: u32,
+}
+
+impl WisunModeParams {
+    const fn new(bitrate: u32, deviation: u32) -> Self {
+        Self {bitrate, deviation, rx_bw: 2 * deviation + bitrate}
+    }
+}
+
+/// Modulation parameters of a WiSUN operating mode
+pub fn wisun_mode_params(mode: WisunMode) -> WisunModeParams {
+    match mode {
+        WisunMode::Mode1a => WisunModeParams::new( 50_000,  12_500),
+        WisunMode::Mode1b => WisunModeParams::new( 50_000,  25_000),
+        WisunMode::Mode2a => WisunModeParams::new(100_000,  25_000),
+        WisunMode::Mode2b => WisunModeParams::new(100_000,  50_000),
+        WisunMode::Mode3  => WisunModeParams::new(150_000,  37_500),
+        WisunMode::Mode4a => WisunModeParams::new(200_000,  50_000),
+        WisunMode::Mode4b => WisunModeParams::new(200_000, 100_000),
+        WisunMode::Mode5  => WisunModeParams::new(300_000,  75_000),
+    }
+}
+
+/// WiSUN regulatory region: define the band used by the channel plan
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub enum WisunRegion {
+    /// Europe: 863-870 MHz
+    Eu,
+    /// North America: 902-928 MHz
+    Na,
+    /// Japan: 920-928 MHz
+    Jp,
+    /// India: 865-867 MHz
+    In,
+}
+
+/// Channel plan of an operating class: channel i is at chan0 + i * spacing
+#[derive(Debug, Clone, Copy, Format, PartialEq)]
+pub struct WisunChanPlan {
+    /// Center frequency of channel 0 in Hz (ChanCenterFreq0)
+    pub chan0: u32,
+    /// Channel spacing in Hz
+    pub spacing: u32,
+    /// Number of channels
+    pub count: u16,
+}
+
+impl WisunChanPlan {
+    const fn new(chan0: u32, spacing: u32, count: u16) -> Self {
+        Self {chan0, spacing, count}
+    }
+
+    /// Center frequency of a channel, None if the channel is outside the plan
+    pub fn channel_freq(&self, chan: u16) -> Option<u32> {
+        if chan >= self.count {
+            return None;
+        }
+        Some(self.chan0 + chan as u32 * self.spacing)
+    }
+}
+
+/// Channel plans per region and operating class, from the Wi-SUN FAN PHY regional parameters,
+/// with the modes allowed in each class.
+///
+/// | Region | Class | Chan0     | Spacing | Channels | Modes     |
+/// |--------|-------|-----------|---------|----------|-----------|
+/// | EU     | 1     | 863.1 MHz | 100 kHz |    69    | 1a        |
+/// | EU     | 2     | 863.1 MHz | 200 kHz |    35    | 2a, 3     |
+/// | NA     | 1     | 902.2 MHz | 200 kHz |   129    | 1a, 1b    |
+/// | NA     | 2     | 902.4 MHz | 400 kHz |    64    | 2a, 3     |
+/// | NA     | 3     | 902.6 MHz | 600 kHz |    42    | 4a, 5     |
+/// | JP     | 1     | 920.6 MHz | 200 kHz |    38    | 1b        |
+/// | JP     | 2     | 920.9 MHz | 400 kHz |    18    | 2b        |
+/// | JP     | 3     | 920.8 MHz | 600 kHz |    12    | 4b        |
+/// | IN     | 1     | 865.1 MHz | 100 kHz |    19    | 1a        |
+/// | IN     | 2     | 865.1 MHz | 200 kHz |    10    | 2a, 3     |
+const WISUN_PLANS: [(WisunRegion, u8, WisunChanPlan); 10] = [
+    (WisunRegion::Eu, 1, WisunChanPlan::new(863_100_000, 100_000,  69)),
+    (WisunRegion::Eu, 2, WisunChanPlan::new(863_100_000, 200_000,  35)),
+    (WisunRegion::Na, 1, WisunChanPlan::new(902_200_000, 200_000, 129)),
+    (WisunRegion::Na, 2, WisunChanPlan::new(902_400_000, 400_000,  64)),
+    (WisunRegion::Na, 3, WisunChanPlan::new(902_600_000, 600_000,  42)),
+    (WisunRegion::Jp, 1, WisunChanPlan::new(920_600_000, 200_000,  38)),
+    (WisunRegion::Jp, 2, WisunChanPlan::new(920_900_000, 400_000,  18)),
+    (WisunRegion::Jp, 3, WisunChanPlan::new(920_800_000, 600_000,  12)),
+    (WisunRegion::In, 1, WisunChanPlan::new(865_100_000, 100_000,  19)),
+    (WisunRegion::In, 2, WisunChanPlan::new(865_100_000, 200_000,  10)),
+];
+
+impl WisunRegion {
+    /// Band edges in Hz
+    pub fn band(&self) -> (u32, u32) {
+        match self {
+            WisunRegion::Eu => (863_000_000, 870_000_000),
+            WisunRegion::Na => (902_000_000, 928_000_000),
+            WisunRegion::Jp => (920_400_000, 928_000_000),
+            WisunRegion::In => (865_000_000, 867_000_000),
+        }
+    }
+
+    /// Operating class using a mode in this region, None if the mode is not allowed in the region
+    pub fn op_class(&self, mode: WisunMode) -> Option<u8> {
+        use WisunMode::*;
+        match (self, mode) {
+            (WisunRegion::Eu, Mode1a) => Some(1),
+            (WisunRegion::Eu, Mode2a | Mode3) => Some(2),
+            (WisunRegion::Na, Mode1a | Mode1b) => Some(1),
+            (WisunRegion::Na, Mode2a | Mode3) => Some(2),
+            (WisunRegion::Na, Mode4a | Mode5) => Some(3),
+            (WisunRegion::Jp, Mode1b) => Some(1),
+            (WisunRegion::Jp, Mode2b) => Some(2),
+            (WisunRegion::Jp, Mode4b) => Some(3),
+            (WisunRegion::In, Mode1a) => Some(1),
+            (WisunRegion::In, Mode2a | Mode3) => Some(2),
+            _ => None,
+        }
+    }
+
+    /// Channel plan for a mode, None if the mode is not allowed in the region
+    pub fn plan(&self, mode: WisunMode) -> Option<WisunChanPlan> {
+        let class = self.op_class(mode)?;
+        WISUN_PLANS.iter()
+            .find(|(r, c, _)| r == self && *c == class)
+            .map(|&(_, _, plan)| plan)
+    }
+
+    /// Number of channels available for a mode (0 if the mode is not allowed in the region)
+    pub fn channels(&self, mode: WisunMode) -> u16 {
+        self.plan(mode).map(|p| p.count).unwrap_or(0)
+    }
+
+    /// Center frequency of a channel, None if the channel or mode is not part of the region plans
+    pub fn channel_freq(&self, mode: WisunMode, chan: u16) -> Option<u32> {
+        self.plan(mode)?.channel_freq(chan)
+    }
+
+    /// Cycle through all regions (EU, NA, JP, IN)
+    pub fn next(&mut self) {
+        *self = match self {
+            WisunRegion::Eu => WisunRegion::Na,
+            WisunRegion::Na => WisunRegion::Jp,
+            WisunRegion::Jp => WisunRegion::In,
+            WisunRegion::In => WisunRegion::Eu,
+        }
+    }
+}
+
+/// Configure the radio for a WiSUN operating mode on a given channel
+/// Chip must be in standby or FS and the packet type set to WiSUN.
+/// Channel index wrap around the number of channels available in the region.
+/// Return the RF frequency selected, or UnsupportedMode if the mode is not allowed in the region.
+pub async fn set_wisun_mode(lr2021: &mut Lr2021Stm32, mode: WisunMode, region: WisunRegion, chan: u16, params: WisunPacketParams) -> Result<u32, AppError> {
+    let plan = region.plan(mode).ok_or(AppError::UnsupportedMode(mode as u8))?;
+    let rf = plan.chan0 + (chan % plan.count) as u32 * plan.spacing;
+    lr2021.set_rf(rf).await?;
+    let rx_bw = rx_bw_from_hz(wisun_mode_params(mode).rx_bw);
+    lr2021.set_wisun_modulation(mode, rx_bw).await?;
+    lr2021.set_wisun_packet(params).await?;
+    Ok(rf)
+}
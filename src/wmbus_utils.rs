@@ -0,0 +1,206 @@
+use defmt::{write, Format, Formatter};
+use lr2021::wmbus::{WmbusFormat, WmbusMode, WmbusSubBand};
+
+use crate::error::AppError;
+
+/// Sub-bands for modes with a single sub-band
+const SUB_BANDS_A: [WmbusSubBand; 1] = [WmbusSubBand::A];
+/// Sub-bands for N modes (channels 1a/1b, 2a/2b, 3a/3b)
+const SUB_BANDS_AB: [WmbusSubBand; 2] = [WmbusSubBand::A, WmbusSubBand::B];
+
+/// Extension of the WMBus mode with the EN 13757-4 frequency plan
+///
+/// | Mode     | Band    | Channels                                       | Sub-bands |
+/// |----------|---------|------------------------------------------------|-----------|
+/// | S        | 868 MHz | 1: 868.30 MHz                                  | a         |
+/// | T1       | 868 MHz | 1: 868.95 MHz                                  | a         |
+/// | R2       | 868 MHz | 10: 868.33 MHz + n*60 kHz                      | a         |
+/// | C1       | 868 MHz | 1: 868.95 MHz                                  | a         |
+/// | N 4.8k   | 169 MHz | 3: 169.406 / 169.431 / 169.456 MHz (+12.5 kHz) | a, b      |
+/// | N 19.2k  | 169 MHz | 1: 169.4375 MHz                                | a         |
+/// | F2       | 433 MHz | 1: 433.82 MHz                                  | a         |
+pub trait WmbusModeExt {
+    /// Number of channels available
+    fn channels(&self) -> u8;
+    /// Sub-bands available on each channel
+    fn sub_bands(&self) -> &'static [WmbusSubBand];
+    /// RF frequency of a channel/sub-band: OutOfRange with the channel index if the channel does not exist,
+    /// UnsupportedMode with the sub-band if it is not part of the mode plan
+    fn checked_rf(&self, chan: u8, sub_band: WmbusSubBand) -> Result<u32, AppError>;
+}
+
+impl WmbusModeExt for WmbusMode {
+    fn channels(&self) -> u8 {
+        match self {
+            WmbusMode::ModeR2 => 10,
+            WmbusMode::ModeN4p8 => 3,
+            _ => 1,
+        }
+    }
+
+    fn sub_bands(&self) -> &'static [WmbusSubBand] {
+        match self {
+            WmbusMode::ModeN4p8 => &SUB_BANDS_AB,
+            _ => &SUB_BANDS_A,
+        }
+    }
+
+    fn checked_rf(&self, chan: u8, sub_band: WmbusSubBand) -> Result<u32, AppError> {
+        if chan >= self.channels() {
+            return Err(AppError::OutOfRange(chan as u32));
+        }
+        if !self.sub_bands().iter().any(|&sb| sb as u8 == sub_band as u8) {
+            return Err(AppError::UnsupportedMode(sub_band as u8));
+        }
+        Ok(self.rf(chan, sub_band))
+    }
+}
+
+/// Compute the wM-Bus CRC (EN 13757: polynomial 0x3D65, init 0, inverted output)
+pub fn wmbus_crc(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {(crc << 1) ^ 0x3D65} else {crc << 1};
+        }
+    }
+    !crc
+}
+
+/// Check the CRC of a block followed by its CRC (MSB first)
+fn check_block(block: &[u8], crc: &[u8]) -> bool {
+    wmbus_crc(block) == u16::from_be_bytes([crc[0], crc[1]])
+}
+
+/// Size of the first block (L, C, M, A fields)
+const BLOCK1_LEN: usize = 10;
+/// Size of the following blocks in Format A
+const BLOCK_A_LEN: usize = 16;
+/// Maximum size of the first two blocks in Format B (including the CRC)
+const BLOCK12_B_LEN: usize = 128;
+
+/// Check all block CRCs of a raw frame and copy its content without the CRCs into dst
+///  - Format A: L-field excludes the CRCs, first block has 10 bytes, then blocks of 16 bytes, each followed by a CRC
+///  - Format B: L-field includes the CRCs, first 128 bytes (at most) protected by one CRC, the remaining by a second CRC
+///
+/// Return the number of bytes written in dst, None if the frame is truncated, dst too small or a CRC is wrong
+pub fn wmbus_remove_crc(format: WmbusFormat, data: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let l_field = *data.first()? as usize;
+    match format {
+        WmbusFormat::FormatA => {
+            let mut remaining = l_field + 1;
+            let mut blk_len = BLOCK1_LEN;
+            let (mut i, mut o) = (0, 0);
+            while remaining > 0 {
+                let n = remaining.min(blk_len);
+                let block = data.get(i..i+n)?;
+                if !check_block(block, data.get(i+n..i+n+2)?) {
+                    return None;
+                }
+                dst.get_mut(o..o+n)?.copy_from_slice(block);
+                i += n + 2;
+                o += n;
+                remaining -= n;
+                blk_len = BLOCK_A_LEN;
+            }
+            Some(o)
+        }
+        WmbusFormat::FormatB => {
+            let total = l_field + 1;
+            let mut o = 0;
+            let mut start = 0;
+            while start < total {
+                let end = total.min(start + BLOCK12_B_LEN);
+                if end < start + 2 {
+                    return None;
+                }
+                let block = data.get(start..end-2)?;
+                // Second CRC only covers the last block, the first one covers block 1 and 2
+                if !check_block(block, data.get(end-2..end)?) {
+                    return None;
+                }
+                dst.get_mut(o..o+block.len())?.copy_from_slice(block);
+                o += block.len();
+                start = end;
+            }
+            Some(o)
+        }
+    }
+}
+
+/// Manufacturer ID: three letters packed on 15 bits (5 bits per letter, 'A' = 1)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WmbusManufacturer(pub u16);
+
+impl WmbusManufacturer {
+    /// Manufacturer code as three ASCII letters
+    pub fn letters(&self) -> [u8; 3] {
+        [
+            ((self.0 >> 10) & 0x1F) as u8 + 64,
+            ((self.0 >>  5) & 0x1F) as u8 + 64,
+            ( self.0        & 0x1F) as u8 + 64,
+        ]
+    }
+}
+
+impl Format for WmbusManufacturer {
+    fn format(&self, fmt: Formatter) {
+        let [a, b, c] = self.letters();
+        write!(fmt, "{}{}{}", a as char, b as char, c as char);
+    }
+}
+
+/// wM-Bus data link layer header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WmbusHdr {
+    /// Length field
+    pub len: u8,
+    /// Control field (frame type: SND-NR, SND-IR, ...)
+    pub c_field: u8,
+    /// Manufacturer of the meter
+    pub manufacturer: WmbusManufacturer,
+    /// Identification number (8 BCD digits)
+    pub id: u32,
+    /// Version of the device
+    pub version: u8,
+    /// Device type (water, gas, heat, ...)
+    pub dev_type: u8,
+    /// Control information field: type of the application layer
+    pub ci_field: u8,
+}
+
+impl WmbusHdr {
+    /// Size of the header (including the CI-field)
+    pub const LEN: usize = 11;
+
+    /// Parse the header of a frame without CRC (see wmbus_remove_crc)
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::LEN {
+            return None;
+        }
+        Some(Self {
+            len: bytes[0],
+            c_field: bytes[1],
+            manufacturer: WmbusManufacturer(u16::from_le_bytes([bytes[2], bytes[3]])),
+            id: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            version: bytes[8],
+            dev_type: bytes[9],
+            ci_field: bytes[10],
+        })
+    }
+}
+
+impl Format for WmbusHdr {
+    fn format(&self, fmt: Formatter) {
+        write!(fmt, "[{}] {} {:08x} v{} type={:02x} | C={:02x} CI={:02x}",
+            self.len,
+            self.manufacturer,
+            self.id,
+            self.version,
+            self.dev_type,
+            self.c_field,
+            self.ci_field,
+        );
+    }
+}